@@ -0,0 +1,184 @@
+use std::{error::Error, path::{Component, Path, PathBuf}};
+
+use log::debug;
+use regex::Regex;
+use walkdir::WalkDir;
+
+/// Name of the directory artifacts are stored in, within the directory of a run
+pub const ARTIFACT_DIR: &str = "artifacts";
+
+/// Translates a glob pattern into a regex matching whole relative paths
+/// `*` and `?` don't match `/`, `**` matches any number of directories.
+pub fn glob_to_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    // Also matches no directory at all
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            },
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re)
+}
+
+/// Parses list of glob patterns, returning the first parse error if any
+pub fn parse_globs(globs: &[String]) -> Result<Vec<Regex>, Box<dyn Error>> {
+    globs.iter()
+        .map(|g| glob_to_regex(g).map_err(|e| e.into()))
+        .collect()
+}
+
+/// Returns location of artifact path within dir, if path stays inside of it
+pub fn artifact_file(dir: &Path, path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    if path.components().all(|c| matches!(c, Component::Normal(_))) {
+        Some(dir.join(path))
+    } else {
+        None
+    }
+}
+
+/// Lists files of dir matching any of patterns along with their relative
+/// paths, which always use `/` as separator
+fn matching_files(dir: &Path, patterns: &[Regex]) -> Result<Vec<(PathBuf, String)>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    // Symlinks are not followed, they could point outside of dir
+    for entry in WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel = entry.path().strip_prefix(dir)?;
+        let rel = rel.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if patterns.iter().any(|p| p.is_match(&rel)) {
+            files.push((entry.path().to_path_buf(), rel));
+        }
+    }
+    Ok(files)
+}
+
+/// Removes files of dir matching any of patterns, except for the ones keep
+/// returns true for
+/// Run before tests, so artifacts left behind by earlier runs aren't
+/// collected again. Returns the relative paths of all removed files.
+pub fn clear(dir: &Path, patterns: &[Regex], keep: impl Fn(&str) -> bool) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut removed = Vec::new();
+    for (path, rel) in matching_files(dir, patterns)? {
+        if keep(&rel) {
+            continue;
+        }
+        std::fs::remove_file(&path)?;
+        debug!("removed stale artifact {}", rel.as_str());
+        removed.push(rel);
+    }
+    Ok(removed)
+}
+
+/// Copies files of workspace matching any of patterns to dest, keeping
+/// their relative paths
+/// Returns the relative paths of all copied files.
+pub fn collect(workspace: &Path, patterns: &[Regex], dest: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut collected = Vec::new();
+    for (path, rel) in matching_files(workspace, patterns)? {
+        let target = dest.join(&rel);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(&path, &target)?;
+        debug!("collected artifact {}", rel.as_str());
+        collected.push(rel);
+    }
+    Ok(collected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(glob: &str, path: &str) -> bool {
+        glob_to_regex(glob).unwrap().is_match(path)
+    }
+
+    #[test]
+    fn star_stays_within_directory() {
+        assert!(matches("*.log", "test.log"));
+        assert!(!matches("*.log", "out/test.log"));
+        assert!(matches("out/*.log", "out/test.log"));
+    }
+
+    #[test]
+    fn question_mark_does_not_match_separator() {
+        assert!(matches("out?.txt", "out1.txt"));
+        assert!(!matches("out?.txt", "out/.txt"));
+    }
+
+    #[test]
+    fn double_star_matches_any_number_of_directories() {
+        assert!(matches("**/report.xml", "report.xml"));
+        assert!(matches("**/report.xml", "a/b/report.xml"));
+        assert!(matches("target/**", "target/a/b.txt"));
+        assert!(!matches("**/report.xml", "a/report.xml.bak"));
+    }
+
+    #[test]
+    fn escapes_regex_characters() {
+        assert!(matches("a+b.txt", "a+b.txt"));
+        assert!(!matches("a+b.txt", "aab.txt"));
+        assert!(!matches("a.txt", "abtxt"));
+    }
+
+    #[test]
+    fn keeps_artifact_paths_inside_of_dir() {
+        let dir = Path::new("/runs/1");
+        assert_eq!(artifact_file(dir, "out/a.txt"), Some(dir.join("out/a.txt")));
+        assert_eq!(artifact_file(dir, "../a.txt"), None);
+        assert_eq!(artifact_file(dir, "/etc/passwd"), None);
+    }
+
+    fn workspace(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rt-artifacts-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("workspace/out")).unwrap();
+        std::fs::write(dir.join("workspace/out/a.txt"), "a").unwrap();
+        std::fs::write(dir.join("workspace/out/b.log"), "b").unwrap();
+        std::fs::write(dir.join("workspace/c.txt"), "c").unwrap();
+        dir
+    }
+
+    #[test]
+    fn collects_matching_files() {
+        let dir = workspace("collect");
+        let patterns = parse_globs(&[String::from("out/*.txt")]).unwrap();
+        let collected = collect(&dir.join("workspace"), &patterns, &dir.join("dest")).unwrap();
+        assert_eq!(collected, vec![String::from("out/a.txt")]);
+        assert_eq!(std::fs::read_to_string(dir.join("dest/out/a.txt")).unwrap(), "a");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clears_matching_files_not_kept() {
+        let dir = workspace("clear");
+        let patterns = parse_globs(&[String::from("**/*.txt")]).unwrap();
+        let removed = clear(&dir.join("workspace"), &patterns, |p| p == "c.txt").unwrap();
+        assert_eq!(removed, vec![String::from("out/a.txt")]);
+        assert!(!dir.join("workspace/out/a.txt").exists());
+        assert!(dir.join("workspace/out/b.log").exists());
+        assert!(dir.join("workspace/c.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
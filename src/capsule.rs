@@ -1,10 +1,15 @@
-use std::{error::Error, path::{Path, PathBuf}, process::{Stdio, Output}};
+use std::{collections::{BTreeMap, HashMap, hash_map::DefaultHasher}, error::Error, hash::{Hash, Hasher}, ffi::OsString, io::Write, os::unix::fs::OpenOptionsExt, path::{Path, PathBuf}, process::{Stdio, Output}, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
 
 use async_trait::async_trait;
-use log::{debug, trace};
-use tokio::process::Command;
+use log::{debug, trace, warn};
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use tokio::{io::AsyncWriteExt, process::Command};
 
-use crate::{project::TestOutput, zip::{ZipBlob, ZipFile}};
+use crate::limits::{Cgroup, ResourceLimits, children_cpu_time};
+use crate::pb::{TestStatus, capsule_options::Kind};
+use crate::project::{RunControl, TestEnv, TestOutput, run_command};
+use crate::sandbox::Sandbox;
 
 
 /// Provides a separate environment for tests to be run in
@@ -15,51 +20,164 @@ pub trait Capsule {
 
     async fn encapsulate(&mut self, ident: String, dir: &Path, args: Self::Args) -> Result<(), Self::Error>;
 
-    async fn run_test(&self, cmd: &Vec<String>) -> Result<TestOutput, Self::Error>; 
+    /// Runs test index in env, which is stopped after timeout or once
+    /// control cancels the run
+    async fn run_test(&self, index: usize, cmd: &[String], env: &TestEnv, timeout: Option<Duration>, control: &RunControl) -> Result<TestOutput, Self::Error>;
+
+    /// Returns local directory holding the project directory as left by the
+    /// tests, copying it into scratch if it only exists inside of the capsule
+    async fn workspace(&self, scratch: &Path) -> Result<PathBuf, Self::Error>;
 
     async fn discard(self) -> Result<(), Self::Error>;
 }
 
+/// Capsule a project's tests are run in, along with its arguments
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+#[allow(clippy::large_enum_variant)]
+pub enum CapsuleChoice {
+    #[default]
+    Transparent,
+    Kubernetes(PodOptions),
+    Container(ContainerOptions),
+    Sandbox(SandboxOptions),
+}
+
+impl From<Kind> for CapsuleChoice {
+    fn from(kind: Kind) -> Self {
+        match kind {
+            Kind::Transparent(_) => CapsuleChoice::Transparent,
+            Kind::Kubernetes(options) => CapsuleChoice::Kubernetes(PodOptions::from(options)),
+            Kind::Container(options) => CapsuleChoice::Container(ContainerOptions::from(options)),
+            Kind::Sandbox(options) => CapsuleChoice::Sandbox(SandboxOptions::from(options)),
+        }
+    }
+}
+
+impl From<CapsuleChoice> for crate::pb::CapsuleOptions {
+    fn from(choice: CapsuleChoice) -> Self {
+        let kind = match choice {
+            CapsuleChoice::Transparent => Kind::Transparent(crate::pb::TransparentOptions {}),
+            CapsuleChoice::Kubernetes(options) => Kind::Kubernetes(crate::pb::PodOptions::from(options)),
+            CapsuleChoice::Container(options) => Kind::Container(crate::pb::ContainerOptions::from(options)),
+            CapsuleChoice::Sandbox(options) => Kind::Sandbox(crate::pb::SandboxOptions::from(options)),
+        };
+        crate::pb::CapsuleOptions { kind: Some(kind) }
+    }
+}
+
+/// Server-wide capsule configuration
+#[derive(Clone, Default)]
+pub struct CapsuleConfig {
+    /// Used for projects which don't choose a capsule themselves
+    pub default: CapsuleChoice,
+    /// Base for kubernetes capsules, projects can only add pod options
+    pub kubernetes: Option<KubernetesCapsule>,
+    /// Base for container capsules, projects can only add container options
+    pub container: Option<ContainerCapsule>,
+    /// cgroup v2 directory tests of transparent capsules get sub-groups in,
+    /// only rlimits are applied if unset
+    pub cgroup: Option<PathBuf>,
+}
+
 /// Transparent capsule, same execution as if without
+/// Only resource limits are applied to tests.
+#[derive(Default)]
 pub struct TransparentCapsule {
-    dir: Option<PathBuf>
+    dir: Option<PathBuf>,
+    ident: String,
+    limits: ResourceLimits,
+    /// Directory cgroups of tests are created in
+    cgroup: Option<PathBuf>,
+    /// Makes cgroup names unique within the capsule
+    groups: AtomicUsize,
+}
+
+impl TransparentCapsule {
+    pub fn new(cgroup: Option<PathBuf>) -> Self {
+        TransparentCapsule { cgroup, ..Default::default() }
+    }
+
+    /// Creates a cgroup for the next test, if there is anything to limit
+    fn create_cgroup(&self) -> Option<Cgroup> {
+        let root = self.cgroup.as_ref()?;
+        if self.limits.memory_mb.is_none() && self.limits.processes.is_none() {
+            return None;
+        }
+        let name = format!("{}-{}", self.ident.as_str(), self.groups.fetch_add(1, Ordering::SeqCst));
+        match Cgroup::create(root, &name, &self.limits) {
+            Ok(group) => Some(group),
+            Err(e) => {
+                // Tests still get rlimits
+                warn!("could not create cgroup {} in {}: {}", name.as_str(), root.display(), e);
+                None
+            },
+        }
+    }
+
+    /// Runs cmd in the directory within the capsule's limits, inside of
+    /// sandbox if supplied
+    async fn run_limited(&self, index: usize, cmd: &[String], env: &TestEnv, timeout: Option<Duration>, control: &RunControl, sandbox: Option<&Sandbox>) -> Result<TestOutput, Box<dyn Error>> {
+        if let Some(dir) = &self.dir {
+            let (program, args) = cmd.split_first()
+                .ok_or("Test command is empty")?;
+            let mut command = Command::new(program);
+            // Set working directory
+            command.current_dir(dir.as_path())
+                .args(args);
+            env.apply_to(&mut command);
+            if let Some(sandbox) = sandbox {
+                sandbox.apply_to(&mut command);
+            }
+            // Limits only apply to the command, not to the processes
+            // waiting for it in the sandbox
+            let cgroup = self.create_cgroup();
+            self.limits.apply_to(&mut command, cgroup.as_ref());
+            let cpu_time = children_cpu_time();
+            // Error must not be held while the cgroup is removed
+            let res = run_command(command, None, shell_words::join(cmd), index, timeout, control)
+                .await
+                .map_err(|e| e.to_string());
+            let cpu_time = children_cpu_time().saturating_sub(cpu_time);
+            let exceeded = res.as_ref().ok()
+                .and_then(|output| self.limits.exceeded(output, cpu_time, cgroup.as_ref()));
+            if let Some(group) = cgroup {
+                group.remove().await;
+            }
+            let mut output = res?;
+            if let (Some(limit), TestStatus::Failed) = (exceeded, output.status) {
+                output.status = TestStatus::LimitExceeded;
+                output.exceeded_limit = limit;
+            }
+            Ok(output)
+        } else {
+            Err(Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "Directory not found")))
+        }
+    }
 }
 
 /// Capsule wrapper for test environments without extra encapsulation
 #[async_trait]
 impl Capsule for TransparentCapsule {
     type Error = Box<dyn Error>;
-    type Args = ();
+    type Args = ResourceLimits;
 
-    async fn encapsulate(&mut self, _: String, dir: &Path, _: Self::Args) -> Result<(), Self::Error> {
+    async fn encapsulate(&mut self, ident: String, dir: &Path, limits: Self::Args) -> Result<(), Self::Error> {
         // Don't encapsulate
         self.dir.replace(dir.to_path_buf());
+        self.ident = ident;
+        self.limits = limits;
         Ok(())
     }
 
-    async fn run_test(&self, cmd: &Vec<String>) -> Result<TestOutput, Self::Error> {
-        if let Some(dir) = &self.dir {
-            let output = Command::new(&cmd[0])
-                // Set working directory
-                .current_dir(dir.as_path())
-                .args(&cmd[1..])
-                .stdin(Stdio::null())
-                .output()
-                .await?;
-            debug!("executed test '{}' -> {}",
-                shell_words::join(cmd),
-                output.status.code().map(|x| x.to_string()).unwrap_or("None".to_string()),
-            );
-            // Return test run results
-            Ok((
-                shell_words::join(cmd),
-                output.status.code(),
-                output.stdout,
-                output.stderr
-            ))
-        } else {
-            Err(Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "Directory not found")))
-        }
+    async fn run_test(&self, index: usize, cmd: &[String], env: &TestEnv, timeout: Option<Duration>, control: &RunControl) -> Result<TestOutput, Self::Error> {
+        self.run_limited(index, cmd, env, timeout, control, None).await
+    }
+
+    async fn workspace(&self, _scratch: &Path) -> Result<PathBuf, Self::Error> {
+        // Tests ran in the directory itself
+        self.dir.clone()
+            .ok_or_else(|| "Directory not found".into())
     }
 
     async fn discard(self) -> Result<(), Self::Error> {
@@ -83,28 +201,112 @@ macro_rules! init_and_setter {
     };
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct PodOptions {
     /// image used by pods
     image: Option<String>,
     /// ports to expose
     ports: Option<Vec<u16>>,
-    // TODO: add more options
+    /// when the image is pulled (Always, IfNotPresent or Never)
+    #[serde(default)]
+    image_pull_policy: Option<String>,
+    /// service account pods run as
+    #[serde(default)]
+    service_account: Option<String>,
+    /// resources requested for the test container, e.g. cpu: 500m
+    #[serde(default)]
+    requests: Option<BTreeMap<String, String>>,
+    /// resources the test container is limited to
+    #[serde(default)]
+    limits: Option<BTreeMap<String, String>>,
+    /// environment variables of the test container
+    #[serde(default)]
+    env: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    labels: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    annotations: Option<BTreeMap<String, String>>,
+    /// labels of nodes pods may be scheduled on
+    #[serde(default)]
+    node_selector: Option<BTreeMap<String, String>>,
+    /// taints of nodes pods tolerate
+    #[serde(default)]
+    tolerations: Option<Vec<Toleration>>,
+    /// volumes mounted into the test container
+    #[serde(default)]
+    volumes: Option<Vec<PodVolume>>,
+}
+
+/// Allows pods to be scheduled on nodes with matching taints
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Toleration {
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Equal or Exists
+    #[serde(default)]
+    pub operator: Option<String>,
+    #[serde(default)]
+    pub value: Option<String>,
+    /// NoSchedule, PreferNoSchedule or NoExecute
+    #[serde(default)]
+    pub effect: Option<String>,
+    #[serde(default)]
+    pub toleration_seconds: Option<i64>,
+}
+
+/// Volume mounted into the test container
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PodVolume {
+    pub name: String,
+    pub mount_path: String,
+    #[serde(default)]
+    pub read_only: bool,
+    pub source: VolumeSource,
+}
+
+/// Where the content of a pod volume comes from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VolumeSource {
+    EmptyDir,
+    HostPath { path: String },
+    ConfigMap { name: String },
+    Secret { name: String },
+    PersistentVolumeClaim { claim_name: String },
 }
 
-impl Default for PodOptions {
-    fn default() -> Self {
-        PodOptions { image: None, ports: None }
+/// Merges other map into map, keys of other have preference
+fn merge_map(map: &mut Option<BTreeMap<String, String>>, other: &Option<BTreeMap<String, String>>) {
+    if let Some(other) = other {
+        map.get_or_insert_with(BTreeMap::new)
+            .extend(other.clone());
     }
 }
 
+/// Converts map of protobuf message, empty maps are unset
+fn map_from_pb(map: HashMap<String, String>) -> Option<BTreeMap<String, String>> {
+    if map.is_empty() {
+        None
+    } else {
+        Some(map.into_iter().collect())
+    }
+}
+
+fn map_to_pb(map: Option<BTreeMap<String, String>>) -> HashMap<String, String> {
+    map.unwrap_or_default().into_iter().collect()
+}
+
 impl PodOptions {
     // auto-generate init and setter methods
     init_and_setter!(image, set_image, String);
     init_and_setter!(ports, set_ports, Vec<u16>);
+    init_and_setter!(image_pull_policy, set_image_pull_policy, String);
+    init_and_setter!(service_account, set_service_account, String);
+    init_and_setter!(tolerations, set_tolerations, Vec<Toleration>);
+    init_and_setter!(volumes, set_volumes, Vec<PodVolume>);
 
     /// Merge other instance of PodOptions into this one
-    /// (Other one has preference)
+    /// (Other one has preference, maps are merged key by key)
     pub fn merge(&mut self, other: &Self) -> &mut Self {
         if let Some(image) = other.image.clone() {
             self.set_image(image);
@@ -112,10 +314,28 @@ impl PodOptions {
         if let Some(ports) = other.ports.clone() {
             self.set_ports(ports);
         }
+        if let Some(policy) = other.image_pull_policy.clone() {
+            self.set_image_pull_policy(policy);
+        }
+        if let Some(account) = other.service_account.clone() {
+            self.set_service_account(account);
+        }
+        merge_map(&mut self.requests, &other.requests);
+        merge_map(&mut self.limits, &other.limits);
+        merge_map(&mut self.env, &other.env);
+        merge_map(&mut self.labels, &other.labels);
+        merge_map(&mut self.annotations, &other.annotations);
+        merge_map(&mut self.node_selector, &other.node_selector);
+        if let Some(tolerations) = other.tolerations.clone() {
+            self.set_tolerations(tolerations);
+        }
+        if let Some(volumes) = other.volumes.clone() {
+            self.set_volumes(volumes);
+        }
         self
     }
 
-    /// Print options as strings for use as kubectl options
+    /// Print options as strings for display
     pub fn as_args_str(&self) -> Vec<String> {
         let mut args = Vec::new();
         if let Some(image) = &self.image {
@@ -126,13 +346,306 @@ impl PodOptions {
                 args.push(format!("--port={}", *port));
             }
         }
+        if let Some(policy) = &self.image_pull_policy {
+            args.push(format!("--image-pull-policy={}", policy.as_str()));
+        }
+        if let Some(account) = &self.service_account {
+            args.push(format!("--service-account={}", account.as_str()));
+        }
+        let maps = [
+            ("requests", &self.requests),
+            ("limits", &self.limits),
+            ("env", &self.env),
+            ("labels", &self.labels),
+            ("annotations", &self.annotations),
+            ("node-selector", &self.node_selector),
+        ];
+        for (name, map) in maps {
+            if let Some(map) = map {
+                let pairs: Vec<String> = map.iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+                args.push(format!("--{}={}", name, pairs.join(",")));
+            }
+        }
+        for toleration in self.tolerations.iter().flatten() {
+            args.push(format!("--toleration={}={}:{}",
+                toleration.key.as_deref().unwrap_or_default(),
+                toleration.value.as_deref().unwrap_or_default(),
+                toleration.effect.as_deref().unwrap_or_default(),
+            ));
+        }
+        for volume in self.volumes.iter().flatten() {
+            args.push(format!("--volume={}:{}{}",
+                volume.name.as_str(),
+                volume.mount_path.as_str(),
+                if volume.read_only { ":ro" } else { "" },
+            ));
+        }
         args
     }
+
+    /// Renders pod manifest for a pod called name, which keeps running
+    /// until it is deleted
+    pub fn manifest(&self, name: &str) -> serde_json::Value {
+        let mut container = json!({
+            "name": "test",
+            "image": self.image,
+            "command": ["sleep", "infinity"],
+        });
+        if let Some(policy) = &self.image_pull_policy {
+            container["imagePullPolicy"] = json!(policy);
+        }
+        if let Some(ports) = &self.ports {
+            container["ports"] = ports.iter()
+                .map(|p| json!({ "containerPort": p }))
+                .collect();
+        }
+        if let Some(env) = &self.env {
+            container["env"] = env.iter()
+                .map(|(k, v)| json!({ "name": k, "value": v }))
+                .collect();
+        }
+        if self.requests.is_some() || self.limits.is_some() {
+            let mut resources = json!({});
+            if let Some(requests) = &self.requests {
+                resources["requests"] = json!(requests);
+            }
+            if let Some(limits) = &self.limits {
+                resources["limits"] = json!(limits);
+            }
+            container["resources"] = resources;
+        }
+        if let Some(volumes) = &self.volumes {
+            container["volumeMounts"] = volumes.iter()
+                .map(|v| json!({ "name": v.name, "mountPath": v.mount_path, "readOnly": v.read_only }))
+                .collect();
+        }
+
+        let mut metadata = json!({ "name": name });
+        if let Some(labels) = &self.labels {
+            metadata["labels"] = json!(labels);
+        }
+        if let Some(annotations) = &self.annotations {
+            metadata["annotations"] = json!(annotations);
+        }
+
+        let mut spec = json!({
+            "restartPolicy": "Never",
+            "containers": [container],
+        });
+        if let Some(account) = &self.service_account {
+            spec["serviceAccountName"] = json!(account);
+        }
+        if let Some(selector) = &self.node_selector {
+            spec["nodeSelector"] = json!(selector);
+        }
+        if let Some(tolerations) = &self.tolerations {
+            spec["tolerations"] = tolerations.iter()
+                .map(Toleration::manifest)
+                .collect();
+        }
+        if let Some(volumes) = &self.volumes {
+            spec["volumes"] = volumes.iter()
+                .map(PodVolume::manifest)
+                .collect();
+        }
+
+        json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": metadata,
+            "spec": spec,
+        })
+    }
+}
+
+impl Toleration {
+    fn manifest(&self) -> serde_json::Value {
+        let mut toleration = json!({});
+        if let Some(key) = &self.key {
+            toleration["key"] = json!(key);
+        }
+        if let Some(operator) = &self.operator {
+            toleration["operator"] = json!(operator);
+        }
+        if let Some(value) = &self.value {
+            toleration["value"] = json!(value);
+        }
+        if let Some(effect) = &self.effect {
+            toleration["effect"] = json!(effect);
+        }
+        if let Some(seconds) = self.toleration_seconds {
+            toleration["tolerationSeconds"] = json!(seconds);
+        }
+        toleration
+    }
+}
+
+impl PodVolume {
+    fn manifest(&self) -> serde_json::Value {
+        let mut volume = json!({ "name": self.name });
+        match &self.source {
+            VolumeSource::EmptyDir => volume["emptyDir"] = json!({}),
+            VolumeSource::HostPath { path } => volume["hostPath"] = json!({ "path": path }),
+            VolumeSource::ConfigMap { name } => volume["configMap"] = json!({ "name": name }),
+            VolumeSource::Secret { name } => volume["secret"] = json!({ "secretName": name }),
+            VolumeSource::PersistentVolumeClaim { claim_name } => volume["persistentVolumeClaim"] = json!({ "claimName": claim_name }),
+        }
+        volume
+    }
+}
+
+impl From<crate::pb::PodOptions> for PodOptions {
+    fn from(options: crate::pb::PodOptions) -> Self {
+        PodOptions {
+            image: options.image,
+            ports: if options.ports.is_empty() {
+                None
+            } else {
+                Some(options.ports.into_iter().map(|p| p as u16).collect())
+            },
+            image_pull_policy: options.image_pull_policy,
+            service_account: options.service_account,
+            requests: map_from_pb(options.requests),
+            limits: map_from_pb(options.limits),
+            env: map_from_pb(options.env),
+            labels: map_from_pb(options.labels),
+            annotations: map_from_pb(options.annotations),
+            node_selector: map_from_pb(options.node_selector),
+            tolerations: if options.tolerations.is_empty() {
+                None
+            } else {
+                Some(options.tolerations.into_iter().map(Toleration::from).collect())
+            },
+            volumes: if options.volumes.is_empty() {
+                None
+            } else {
+                Some(options.volumes.into_iter().map(PodVolume::from).collect())
+            },
+        }
+    }
+}
+
+impl From<PodOptions> for crate::pb::PodOptions {
+    fn from(options: PodOptions) -> Self {
+        crate::pb::PodOptions {
+            image: options.image,
+            ports: options.ports.unwrap_or_default().into_iter().map(u32::from).collect(),
+            image_pull_policy: options.image_pull_policy,
+            service_account: options.service_account,
+            requests: map_to_pb(options.requests),
+            limits: map_to_pb(options.limits),
+            env: map_to_pb(options.env),
+            labels: map_to_pb(options.labels),
+            annotations: map_to_pb(options.annotations),
+            node_selector: map_to_pb(options.node_selector),
+            tolerations: options.tolerations.unwrap_or_default().into_iter().map(crate::pb::Toleration::from).collect(),
+            volumes: options.volumes.unwrap_or_default().into_iter().map(crate::pb::PodVolume::from).collect(),
+        }
+    }
+}
+
+impl From<crate::pb::Toleration> for Toleration {
+    fn from(toleration: crate::pb::Toleration) -> Self {
+        Toleration {
+            key: toleration.key,
+            operator: toleration.operator,
+            value: toleration.value,
+            effect: toleration.effect,
+            toleration_seconds: toleration.toleration_seconds,
+        }
+    }
+}
+
+impl From<Toleration> for crate::pb::Toleration {
+    fn from(toleration: Toleration) -> Self {
+        crate::pb::Toleration {
+            key: toleration.key,
+            operator: toleration.operator,
+            value: toleration.value,
+            effect: toleration.effect,
+            toleration_seconds: toleration.toleration_seconds,
+        }
+    }
+}
+
+impl From<crate::pb::PodVolume> for PodVolume {
+    fn from(volume: crate::pb::PodVolume) -> Self {
+        use crate::pb::pod_volume::Source;
+        let source = match volume.source {
+            Some(Source::EmptyDir(_)) | None => VolumeSource::EmptyDir,
+            Some(Source::HostPath(path)) => VolumeSource::HostPath { path },
+            Some(Source::ConfigMap(name)) => VolumeSource::ConfigMap { name },
+            Some(Source::Secret(name)) => VolumeSource::Secret { name },
+            Some(Source::PersistentVolumeClaim(claim_name)) => VolumeSource::PersistentVolumeClaim { claim_name },
+        };
+        PodVolume {
+            name: volume.name,
+            mount_path: volume.mount_path,
+            read_only: volume.read_only,
+            source,
+        }
+    }
+}
+
+impl From<PodVolume> for crate::pb::PodVolume {
+    fn from(volume: PodVolume) -> Self {
+        use crate::pb::pod_volume::Source;
+        let source = match volume.source {
+            VolumeSource::EmptyDir => Source::EmptyDir(crate::pb::EmptyDirVolume {}),
+            VolumeSource::HostPath { path } => Source::HostPath(path),
+            VolumeSource::ConfigMap { name } => Source::ConfigMap(name),
+            VolumeSource::Secret { name } => Source::Secret(name),
+            VolumeSource::PersistentVolumeClaim { claim_name } => Source::PersistentVolumeClaim(claim_name),
+        };
+        crate::pb::PodVolume {
+            name: volume.name,
+            mount_path: volume.mount_path,
+            read_only: volume.read_only,
+            source: Some(source),
+        }
+    }
+}
+
+/// Directory the project is copied to inside of pods
+const POD_REPO_DIR: &str = "/etc/repo";
+/// Time pods get to become ready before the run is given up
+const POD_READY_TIMEOUT: Duration = Duration::from_secs(300);
+/// kubeconfig user the token is passed as
+const KUBE_TOKEN_USER: &str = "remote-test";
+
+/// Default location of the kubeconfig, used by kubectl if KUBECONFIG is unset
+fn home_kubeconfig() -> PathBuf {
+    let home = std::env::var_os("HOME").unwrap_or_default();
+    Path::new(&home).join(".kube").join("config")
+}
+
+/// Maximum length of pod names
+const POD_NAME_MAX_LEN: usize = 63;
+
+/// Name of the pod started for ident
+/// Pod names have to be DNS-1123 labels: lowercase alphanumeric characters
+/// or '-', starting and ending with an alphanumeric one. If ident has to be
+/// shortened, a hash of it keeps names of different runs apart.
+fn pod_name(ident: &str) -> String {
+    let name: String = format!("rtk-capsule-{}", ident).chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    if name.len() <= POD_NAME_MAX_LEN {
+        return name.trim_end_matches('-').to_string();
+    }
+    let mut hasher = DefaultHasher::new();
+    ident.hash(&mut hasher);
+    let suffix = format!("{:016x}", hasher.finish());
+    let prefix = &name[..POD_NAME_MAX_LEN - suffix.len() - 1];
+    format!("{}-{}", prefix.trim_end_matches('-'), suffix)
 }
 
 /// Testwise encapsulation via k8s pods
 ///
 /// Starts up separate pods for each test run
+#[derive(Clone)]
 pub struct KubernetesCapsule {
     // Defaults to kubectl, could be specific executable
     program: Option<String>,
@@ -140,13 +653,29 @@ pub struct KubernetesCapsule {
     token: String,
     // Namespace used to start up pods
     namespace: String,
+    // kubeconfig context, current one if unset
+    context: Option<String>,
     // base config for running pods
     options: PodOptions,
     // identifier for spawned pod
     podname: Option<String>,
+    // temporary kubeconfig holding the token
+    kubeconfig: Option<PathBuf>,
 }
 
 impl KubernetesCapsule {
+    pub fn new(program: Option<String>, token: String, namespace: String, context: Option<String>, options: PodOptions) -> Self {
+        KubernetesCapsule {
+            program,
+            token,
+            namespace,
+            context,
+            options,
+            podname: None,
+            kubeconfig: None,
+        }
+    }
+
     fn program(&self) -> &str {
         if let Some(p) = &self.program {
             p.as_str()
@@ -155,10 +684,61 @@ impl KubernetesCapsule {
         }
     }
 
-    async fn kube_cmd(&self, args: &Vec<String>) -> Result<Output, Box<dyn Error>> {
+    /// Writes a kubeconfig only defining a user with the token, if any
+    /// Command lines are visible to everyone on the host, so the token is
+    /// not passed as an argument.
+    fn write_kubeconfig(&self, podname: &str) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        if self.token.is_empty() {
+            return Ok(None);
+        }
+        let config = json!({
+            "apiVersion": "v1",
+            "kind": "Config",
+            "users": [{ "name": KUBE_TOKEN_USER, "user": { "token": self.token.as_str() } }],
+        });
+        let path = std::env::temp_dir().join(format!("{}.kubeconfig", podname));
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.write_all(config.to_string().as_bytes())?;
+        Ok(Some(path))
+    }
+
+    fn remove_kubeconfig(&self) {
+        if let Some(path) = &self.kubeconfig {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("could not remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Prepares kubectl command with cluster options set
+    fn kube_command(&self, args: &[String]) -> Command {
         debug!("{} {}", self.program(), args.join(" "));
-        let output = Command::new(self.program())
-            .args(&args[..])
+        let mut cmd = Command::new(self.program());
+        cmd.arg(format!("--namespace={}", self.namespace.as_str()));
+        if let Some(context) = &self.context {
+            cmd.arg(format!("--context={}", context.as_str()));
+        }
+        if let Some(kubeconfig) = &self.kubeconfig {
+            // Clusters and contexts still come from the regular kubeconfig
+            let mut files = OsString::from(kubeconfig);
+            files.push(":");
+            match std::env::var_os("KUBECONFIG") {
+                Some(regular) => files.push(regular),
+                None => files.push(home_kubeconfig()),
+            }
+            cmd.env("KUBECONFIG", files);
+            cmd.arg(format!("--user={}", KUBE_TOKEN_USER));
+        }
+        cmd.args(args);
+        cmd
+    }
+
+    async fn kube_cmd(&self, args: &[String]) -> Result<Output, Box<dyn Error>> {
+        let output = self.kube_command(args)
             .stdin(Stdio::null())
             .output()
             .await?;
@@ -168,22 +748,70 @@ impl KubernetesCapsule {
         Ok(output)
     }
 
-    // runs kube_cmd without return code return
-    async fn kube_cmd_silent(&self, args: &Vec<String>) -> Result<(), Box<dyn Error>> {
-        let output = self.kube_cmd(&args).await?;
-        if output.status.code().is_some() && output.status.code().unwrap_or(-1) == 0 {
-            Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("kubectl {} return code: {}", args.join(" "), output.status.code().unwrap_or(-1)))))
+    /// Runs kubectl command, passing input to its stdin
+    async fn kube_cmd_input(&self, args: &[String], input: &[u8]) -> Result<Output, Box<dyn Error>> {
+        let mut child = self.kube_command(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(input).await?;
+        // Close stdin, so kubectl stops reading
+        drop(stdin);
+        let output = child.wait_with_output().await?;
+        trace!("stdout: '{:?}'\nstderr: '{:?}'", output.stdout, output.stderr);
+        debug!("status: {}", output.status.code().unwrap_or(-1));
+        Ok(output)
+    }
+
+    fn check_status(&self, args: &[String], output: &Output) -> Result<(), Box<dyn Error>> {
+        if output.status.code() != Some(0) {
+            Err(Box::new(std::io::Error::other(format!("{} {} return code: {}, {}",
+                self.program(),
+                args.join(" "),
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).trim(),
+            ))))
         } else {
             Ok(())
         }
     }
 
+    // runs kube_cmd, failing on non-zero return codes
+    async fn kube_cmd_silent(&self, args: &[String]) -> Result<(), Box<dyn Error>> {
+        let output = self.kube_cmd(args).await?;
+        self.check_status(args, &output)
+    }
+
+    /// Creates pod from the manifest rendered by options
     async fn kube_run(&self) -> Result<(), Box<dyn Error>> {
-        let mut args = self.options.as_args_str();
-        // Insert kubectl subcmd and podname in front
-        args.insert(0, String::from("run"));
-        args.insert(1, self.podname.clone().unwrap());
-        self.kube_cmd_silent(&args).await
+        if self.options.image.is_none() {
+            return Err("No image configured for kubernetes capsule".into());
+        }
+        let manifest = self.options.manifest(self.podname.as_deref().unwrap());
+        trace!("pod manifest: {}", manifest);
+        let args = vec![
+            "create".to_string(),
+            "--filename=-".to_string(),
+        ];
+        let output = self.kube_cmd_input(&args, manifest.to_string().as_bytes()).await?;
+        self.check_status(&args, &output)
+    }
+
+    /// Waits for the pod's containers to be up, so files can be copied
+    async fn kube_wait_ready(&self) -> Result<(), Box<dyn Error>> {
+        if let Some(podname) = self.podname.clone() {
+            let args = vec![
+                "wait".to_string(),
+                "--for=condition=Ready".to_string(),
+                format!("--timeout={}s", POD_READY_TIMEOUT.as_secs()),
+                format!("pod/{}", podname),
+            ];
+            self.kube_cmd_silent(&args).await
+        } else {
+            panic!("No podname defined");
+        }
     }
 
     async fn kube_cp(&self, mut src: String, mut dest: String, to_pod: bool) -> Result<(), Box<dyn Error>> {
@@ -204,15 +832,37 @@ impl KubernetesCapsule {
         }
     }
 
-    async fn kube_exec(&self, p_args: &Vec<String>) -> Result<Output, Box<dyn Error>> {
+    /// Prepares kubectl command executing p_args inside of the pod, passing
+    /// stdin on to it
+    fn kube_exec(&self, p_args: &[String]) -> Command {
         if let Some(podname) = self.podname.clone() {
             // insert exec cmd items
-            let mut args = Vec::new();
-            args.push("exec".to_string());
-            args.push(podname);
-            args.push("--".to_string());
-            args.append(&mut p_args.clone());
-            self.kube_cmd(&args).await
+            let mut args = vec![
+                "exec".to_string(),
+                "--stdin".to_string(),
+                podname,
+                "--".to_string(),
+            ];
+            args.extend_from_slice(p_args);
+            self.kube_command(&args)
+        } else {
+            panic!("No podname defined");
+        }
+    }
+
+    /// Kills test process recorded in pid_file inside of the pod
+    async fn kube_kill_test(&self, pid_file: String) -> Result<(), Box<dyn Error>> {
+        if let Some(podname) = self.podname.clone() {
+            let args = vec![
+                "exec".to_string(),
+                podname,
+                "--".to_string(),
+                "sh".to_string(),
+                "-c".to_string(),
+                KILL_RECORDED_SCRIPT.to_string(),
+                pid_file,
+            ];
+            self.kube_cmd_silent(&args).await
         } else {
             panic!("No podname defined");
         }
@@ -224,11 +874,23 @@ impl KubernetesCapsule {
             args.push("delete".to_string());
             args.push("pod".to_string());
             args.push(podname);
+            args.push("--ignore-not-found".to_string());
             self.kube_cmd_silent(&args).await
         } else {
             panic!("No podname defined");
         }
     }
+
+    /// Starts pod and copies dir into it
+    async fn start_pod(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        self.kube_run()
+            .await?;
+        self.kube_wait_ready()
+            .await?;
+        let src = dir.to_string_lossy();
+        self.kube_cp(src.to_string(), String::from(POD_REPO_DIR), true)
+            .await
+    }
 }
 
 #[async_trait]
@@ -239,27 +901,500 @@ impl Capsule for KubernetesCapsule {
 
     /// Use to start up pod
     async fn encapsulate(&mut self, ident: String, dir: &Path, args: Self::Args) -> Result<(), Self::Error> {
-        self.podname = Some(format!("rtk-capsule-{}", ident.as_str()));
-        let options = self.options.clone()
-            .merge(&args);
-        // Create pod with options
-        self.kube_run()
+        let podname = pod_name(&ident);
+        self.options.merge(&args);
+        self.kubeconfig = self.write_kubeconfig(&podname)?;
+        self.podname = Some(podname);
+        // Create pod with options and copy files to it
+        if let Err(e) = self.start_pod(dir).await.map_err(|e| e.to_string()) {
+            // Don't leave a half set up pod behind
+            if let Err(e) = self.kube_delete_pod().await {
+                warn!("could not delete pod: {}", e);
+            }
+            self.remove_kubeconfig();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Run test command inside of the pod
+    async fn run_test(&self, index: usize, cmd: &[String], env: &TestEnv, timeout: Option<Duration>, control: &RunControl) -> Result<TestOutput, Self::Error> {
+        if cmd.is_empty() {
+            return Err("Test command is empty".into());
+        }
+        let pid_file = remote_pid_file(index);
+        // kubectl exec can't set the working directory
+        let mut args = vec![
+            String::from("sh"),
+            String::from("-c"),
+            format!("cd {} && {}", POD_REPO_DIR, RECORD_PID_SCRIPT),
+            pid_file.clone(),
+        ];
+        args.extend_from_slice(cmd);
+        // Environment has to be set up inside of the pod
+        let input = env.as_shell_script().into_bytes();
+        let output = run_command(self.kube_exec(&args), Some(input), shell_words::join(cmd), index, timeout, control)
+            .await
+            .map_err(|e| e.to_string())?;
+        if was_stopped(&output) {
+            if let Err(e) = self.kube_kill_test(pid_file).await {
+                warn!("could not kill test {} in pod: {}", index, e);
+            }
+        }
+        Ok(output)
+    }
+
+    /// Copy project directory back from the pod
+    async fn workspace(&self, scratch: &Path) -> Result<PathBuf, Self::Error> {
+        let dest = scratch.to_string_lossy();
+        self.kube_cp(String::from(POD_REPO_DIR), dest.to_string(), false)
             .await?;
-        // copy files to pod
-        let src = dir.to_string_lossy();
-        self.kube_cp(src.to_string(), String::from("/etc/repo/"), true)
+        Ok(scratch.to_path_buf())
+    }
+
+    async fn discard(self) -> Result<(), Self::Error> {
+        // Delete running pod
+        let res = self.kube_delete_pod().await;
+        self.remove_kubeconfig();
+        res
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ContainerOptions {
+    /// image containers are started from
+    image: Option<String>,
+    /// network containers are attached to
+    network: Option<String>,
+    /// copy project into the container instead of mounting it
+    copy: Option<bool>,
+}
+
+impl ContainerOptions {
+    // auto-generate init and setter methods
+    init_and_setter!(image, set_image, String);
+    init_and_setter!(network, set_network, String);
+    init_and_setter!(copy, set_copy, bool);
+
+    /// Merge other instance of ContainerOptions into this one
+    /// (Other one has preference)
+    pub fn merge(&mut self, other: &Self) -> &mut Self {
+        if let Some(image) = other.image.clone() {
+            self.set_image(image);
+        }
+        if let Some(network) = other.network.clone() {
+            self.set_network(network);
+        }
+        if let Some(copy) = other.copy {
+            self.set_copy(copy);
+        }
+        self
+    }
+
+    /// Print options as strings for display
+    pub fn as_args_str(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(image) = &self.image {
+            args.push(format!("--image={}", image.as_str()));
+        }
+        if let Some(network) = &self.network {
+            args.push(format!("--network={}", network.as_str()));
+        }
+        if let Some(copy) = self.copy {
+            args.push(format!("--copy={}", copy));
+        }
+        args
+    }
+}
+
+impl From<crate::pb::ContainerOptions> for ContainerOptions {
+    fn from(options: crate::pb::ContainerOptions) -> Self {
+        ContainerOptions {
+            image: options.image,
+            network: options.network,
+            copy: options.copy,
+        }
+    }
+}
+
+impl From<ContainerOptions> for crate::pb::ContainerOptions {
+    fn from(options: ContainerOptions) -> Self {
+        crate::pb::ContainerOptions {
+            image: options.image,
+            network: options.network,
+            copy: options.copy,
+        }
+    }
+}
+
+/// Directory the project is available at inside of containers
+const CONTAINER_REPO_DIR: &str = "/repo";
+
+/// Shell script running its arguments in a new session and recording the
+/// session's PID in the file given as $0
+/// Killing the local exec client leaves the test running remotely, so it
+/// has to be killed by that PID, along with the processes it started. setsid
+/// is not exec'd, it would fork and return right away if called by a process
+/// group leader. The environment is set up from the commands passed on
+/// stdin, see TestEnv::as_shell_script.
+const RECORD_PID_SCRIPT: &str = r#"setsid sh -c 'eval "$(cat)" && echo $$ >"$0" && exec "$@"' "$0" "$@""#;
+/// Shell script killing the process group recorded in the file given as $0
+const KILL_RECORDED_SCRIPT: &str = r#"exec kill -KILL -- -"$(cat "$0")""#;
+
+/// File the PID of test index is recorded in, within containers and pods
+fn remote_pid_file(index: usize) -> String {
+    format!("/tmp/rt-{}.pid", index)
+}
+
+/// Returns whether output is of a test that was killed
+fn was_stopped(output: &TestOutput) -> bool {
+    matches!(output.status, TestStatus::TimedOut | TestStatus::Cancelled)
+}
+
+/// Testwise encapsulation via docker or podman containers
+///
+/// Starts up a separate container for each test run, tests are executed in
+/// it one by one. Stopped tests are killed inside of the container, along
+/// with the processes they started.
+#[derive(Clone)]
+pub struct ContainerCapsule {
+    // Defaults to docker, could be podman or specific executable
+    program: Option<String>,
+    // base config for running containers
+    options: ContainerOptions,
+    // identifier for started container
+    name: Option<String>,
+    // project directory on the host
+    dir: Option<PathBuf>,
+}
+
+impl ContainerCapsule {
+    pub fn new(program: Option<String>, options: ContainerOptions) -> Self {
+        ContainerCapsule {
+            program,
+            options,
+            name: None,
+            dir: None,
+        }
+    }
+
+    fn program(&self) -> &str {
+        if let Some(p) = &self.program {
+            p.as_str()
+        } else {
+            "docker"
+        }
+    }
+
+    fn name(&self) -> Result<&str, Box<dyn Error>> {
+        self.name.as_deref()
+            .ok_or_else(|| "No container started".into())
+    }
+
+    async fn runtime_cmd(&self, args: &[String]) -> Result<(), Box<dyn Error>> {
+        debug!("{} {}", self.program(), args.join(" "));
+        let output = Command::new(self.program())
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
             .await?;
+        trace!("stdout: '{:?}'\nstderr: '{:?}'", output.stdout, output.stderr);
+        if output.status.code() != Some(0) {
+            return Err(Box::new(std::io::Error::other(format!("{} {} return code: {}, {}",
+                self.program(),
+                args.join(" "),
+                output.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&output.stderr).trim(),
+            ))));
+        }
         Ok(())
     }
 
-    /// 
-    async fn run_test(&self, cmd: &Vec<String>) -> Result<TestOutput, Self::Error> {
-        let output = self.kube_exec(cmd).await?;
-        return Ok((cmd.join(" "), output.status.code(), output.stdout, output.stderr));
+    /// Kills test process recorded in pid_file inside of the container
+    async fn kill_test(&self, pid_file: String) -> Result<(), Box<dyn Error>> {
+        let args = vec![
+            String::from("exec"),
+            self.name()?.to_string(),
+            String::from("sh"),
+            String::from("-c"),
+            String::from(KILL_RECORDED_SCRIPT),
+            pid_file,
+        ];
+        self.runtime_cmd(&args).await
+    }
+
+    /// Removes container along with everything still running in it
+    async fn remove(&self, name: String) -> Result<(), Box<dyn Error>> {
+        self.runtime_cmd(&[String::from("rm"), String::from("--force"), name]).await
+    }
+}
+
+#[async_trait]
+impl Capsule for ContainerCapsule {
+    type Error = Box<dyn Error>;
+    type Args = ContainerOptions;
+
+    /// Starts container, mounting or copying dir into it
+    async fn encapsulate(&mut self, ident: String, dir: &Path, args: Self::Args) -> Result<(), Self::Error> {
+        let name = format!("rtc-capsule-{}", ident.as_str());
+        self.options.merge(&args);
+        let options = self.options.clone();
+        let image = options.image.clone()
+            .ok_or("No image configured for container capsule")?;
+        let copy = options.copy.unwrap_or(false);
+        let dir = std::fs::canonicalize(dir)?;
+        let mut run = vec![
+            String::from("run"),
+            String::from("--detach"),
+            format!("--name={}", name.as_str()),
+            format!("--workdir={}", CONTAINER_REPO_DIR),
+        ];
+        if let Some(network) = &options.network {
+            run.push(format!("--network={}", network.as_str()));
+        }
+        if !copy {
+            run.push(format!("--volume={}:{}", dir.to_string_lossy(), CONTAINER_REPO_DIR));
+        }
+        // Keep container alive until it is discarded
+        run.extend([image, String::from("sleep"), String::from("infinity")]);
+        self.runtime_cmd(&run).await?;
+        self.name = Some(name.clone());
+        self.dir = Some(dir.clone());
+        if copy {
+            let cp = vec![
+                String::from("cp"),
+                format!("{}/.", dir.to_string_lossy()),
+                format!("{}:{}", name.as_str(), CONTAINER_REPO_DIR),
+            ];
+            if let Err(e) = self.runtime_cmd(&cp).await.map_err(|e| e.to_string()) {
+                // Don't leave the container behind
+                if let Err(e) = self.remove(name).await {
+                    warn!("could not remove container: {}", e);
+                }
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Run test command inside of the container
+    async fn run_test(&self, index: usize, cmd: &[String], env: &TestEnv, timeout: Option<Duration>, control: &RunControl) -> Result<TestOutput, Self::Error> {
+        if cmd.is_empty() {
+            return Err("Test command is empty".into());
+        }
+        let pid_file = remote_pid_file(index);
+        let mut command = Command::new(self.program());
+        command.args(["exec", "--interactive", self.name()?, "sh", "-c", RECORD_PID_SCRIPT, pid_file.as_str()])
+            .args(cmd);
+        debug!("{} exec {} {}", self.program(), self.name()?, shell_words::join(cmd));
+        // Environment has to be set up inside of the container
+        let input = env.as_shell_script().into_bytes();
+        let output = run_command(command, Some(input), shell_words::join(cmd), index, timeout, control)
+            .await
+            .map_err(|e| e.to_string())?;
+        if was_stopped(&output) {
+            if let Err(e) = self.kill_test(pid_file).await {
+                warn!("could not kill test {} in container: {}", index, e);
+            }
+        }
+        Ok(output)
+    }
+
+    async fn workspace(&self, scratch: &Path) -> Result<PathBuf, Self::Error> {
+        if !self.options.copy.unwrap_or(false) {
+            // Tests ran in the mounted directory
+            return self.dir.clone()
+                .ok_or_else(|| "No container started".into());
+        }
+        let cp = vec![
+            String::from("cp"),
+            format!("{}:{}/.", self.name()?, CONTAINER_REPO_DIR),
+            scratch.to_string_lossy().to_string(),
+        ];
+        self.runtime_cmd(&cp).await?;
+        Ok(scratch.to_path_buf())
     }
 
     async fn discard(self) -> Result<(), Self::Error> {
-        // Delete running pod
-        self.kube_delete_pod().await
+        let name = self.name()?.to_string();
+        self.remove(name).await
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SandboxOptions {
+    /// tests can only reach the loopback interface
+    isolate_network: Option<bool>,
+}
+
+impl SandboxOptions {
+    // auto-generate init and setter methods
+    init_and_setter!(isolate_network, set_isolate_network, bool);
+
+    /// Print options as strings for display
+    pub fn as_args_str(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(isolate) = self.isolate_network {
+            args.push(format!("--isolate-network={}", isolate));
+        }
+        args
+    }
+}
+
+impl From<crate::pb::SandboxOptions> for SandboxOptions {
+    fn from(options: crate::pb::SandboxOptions) -> Self {
+        SandboxOptions {
+            isolate_network: options.isolate_network,
+        }
+    }
+}
+
+impl From<SandboxOptions> for crate::pb::SandboxOptions {
+    fn from(options: SandboxOptions) -> Self {
+        crate::pb::SandboxOptions {
+            isolate_network: options.isolate_network,
+        }
+    }
+}
+
+/// Runs tests in the project directory, inside of unprivileged namespaces
+///
+/// Lighter than a container, but keeps tests from writing outside of the
+/// project directory and from seeing other processes. Requires unprivileged
+/// user namespaces and Linux 5.12 or newer. Resource limits apply as in
+/// transparent capsules.
+pub struct SandboxCapsule {
+    // runs tests in the directory, within limits
+    inner: TransparentCapsule,
+    limits: ResourceLimits,
+    sandbox: Option<Sandbox>,
+}
+
+impl SandboxCapsule {
+    pub fn new(cgroup: Option<PathBuf>, limits: ResourceLimits) -> Self {
+        SandboxCapsule {
+            inner: TransparentCapsule::new(cgroup),
+            limits,
+            sandbox: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Capsule for SandboxCapsule {
+    type Error = Box<dyn Error>;
+    type Args = SandboxOptions;
+
+    async fn encapsulate(&mut self, ident: String, dir: &Path, options: Self::Args) -> Result<(), Self::Error> {
+        // Namespaces are entered by each test on its own
+        self.sandbox = Some(Sandbox::new(dir, options.isolate_network.unwrap_or(false))?);
+        self.inner.encapsulate(ident, dir, self.limits).await
+    }
+
+    async fn run_test(&self, index: usize, cmd: &[String], env: &TestEnv, timeout: Option<Duration>, control: &RunControl) -> Result<TestOutput, Self::Error> {
+        let sandbox = self.sandbox.as_ref()
+            .ok_or_else(|| Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "Directory not found")))?;
+        self.inner.run_limited(index, cmd, env, timeout, control, Some(sandbox)).await
+    }
+
+    async fn workspace(&self, scratch: &Path) -> Result<PathBuf, Self::Error> {
+        // Tests wrote to the directory itself
+        self.inner.workspace(scratch).await
+    }
+
+    async fn discard(self) -> Result<(), Self::Error> {
+        // Namespaces are gone along with their last process
+        self.inner.discard().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses pod options the way they're given in project configurations
+    fn pod_options(config: &str) -> PodOptions {
+        serde_json::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn minimal_pod_manifest() {
+        let manifest = pod_options(r#"{ "image": "alpine" }"#).manifest("rtk-capsule-minimal");
+        assert_eq!(manifest, json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": { "name": "rtk-capsule-minimal" },
+            "spec": {
+                "restartPolicy": "Never",
+                "containers": [{ "name": "test", "image": "alpine", "command": ["sleep", "infinity"] }],
+            },
+        }));
+    }
+
+    #[test]
+    fn pod_manifest_with_all_options() {
+        let options = pod_options(r#"{
+            "image": "alpine",
+            "ports": [8080],
+            "image_pull_policy": "Never",
+            "service_account": "tests",
+            "requests": { "cpu": "500m" },
+            "limits": { "cpu": "1", "memory": "1Gi" },
+            "env": { "RUST_LOG": "debug", "CI": "1" },
+            "labels": { "app": "tests" },
+            "annotations": { "owner": "ci" },
+            "node_selector": { "disk": "ssd" },
+            "tolerations": [
+                { "key": "dedicated", "operator": "Equal", "value": "tests", "effect": "NoSchedule" },
+                { "operator": "Exists", "toleration_seconds": 60 }
+            ],
+            "volumes": [
+                { "name": "cache", "mount_path": "/cache", "source": { "kind": "empty_dir" } },
+                { "name": "data", "mount_path": "/data", "read_only": true, "source": { "kind": "persistent_volume_claim", "claim_name": "data" } },
+                { "name": "token", "mount_path": "/token", "source": { "kind": "secret", "name": "token" } }
+            ]
+        }"#);
+        let manifest = options.manifest("rtk-capsule-full");
+        assert_eq!(manifest["metadata"], json!({ "name": "rtk-capsule-full", "labels": { "app": "tests" }, "annotations": { "owner": "ci" } }));
+        let spec = &manifest["spec"];
+        assert_eq!(spec["serviceAccountName"], "tests");
+        assert_eq!(spec["nodeSelector"], json!({ "disk": "ssd" }));
+        assert_eq!(spec["tolerations"], json!([
+            { "key": "dedicated", "operator": "Equal", "value": "tests", "effect": "NoSchedule" },
+            { "operator": "Exists", "tolerationSeconds": 60 },
+        ]));
+        assert_eq!(spec["volumes"], json!([
+            { "name": "cache", "emptyDir": {} },
+            { "name": "data", "persistentVolumeClaim": { "claimName": "data" } },
+            { "name": "token", "secret": { "secretName": "token" } },
+        ]));
+        let container = &spec["containers"][0];
+        assert_eq!(container["imagePullPolicy"], "Never");
+        assert_eq!(container["ports"], json!([{ "containerPort": 8080 }]));
+        assert_eq!(container["resources"], json!({ "requests": { "cpu": "500m" }, "limits": { "cpu": "1", "memory": "1Gi" } }));
+        assert_eq!(container["env"], json!([{ "name": "CI", "value": "1" }, { "name": "RUST_LOG", "value": "debug" }]));
+        assert_eq!(container["volumeMounts"], json!([
+            { "name": "cache", "mountPath": "/cache", "readOnly": false },
+            { "name": "data", "mountPath": "/data", "readOnly": true },
+            { "name": "token", "mountPath": "/token", "readOnly": false },
+        ]));
+    }
+
+    #[tokio::test]
+    async fn detects_cpu_time_limit_of_tests_ignoring_sigxcpu() {
+        let mut capsule = TransparentCapsule::default();
+        let limits = ResourceLimits { cpu_time: Some(1), ..ResourceLimits::default() };
+        capsule.encapsulate(String::from("cpu"), &std::env::temp_dir(), limits).await.unwrap();
+        let cmd = shell_words::split("sh -c 'trap \"\" XCPU; while :; do :; done'").unwrap();
+        let output = capsule.run_test(0, &cmd, &TestEnv::default(), Some(Duration::from_secs(30)), &RunControl::default()).await.unwrap();
+        assert_eq!(output.signal, Some(libc::SIGKILL));
+        assert_eq!(output.status, TestStatus::LimitExceeded);
+        assert_eq!(output.exceeded_limit, crate::pb::ResourceLimit::CpuTime);
+    }
+
+    #[test]
+    fn pod_manifest_with_only_limits() {
+        let manifest = pod_options(r#"{ "image": "alpine", "limits": { "memory": "256Mi" } }"#).manifest("rtk-capsule-limits");
+        assert_eq!(manifest["spec"]["containers"][0]["resources"], json!({ "limits": { "memory": "256Mi" } }));
     }
 }
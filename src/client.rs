@@ -1,18 +1,119 @@
-use std::{error::Error, future::Future, path::Path, time::{Duration, Instant}};
+use std::{collections::{BTreeMap, BTreeSet, HashMap}, error::Error, future::Future, path::Path, sync::Arc, time::Duration};
 
-use remote_test::{client_errors::ClientError, pb::{Project, ProjectIdentifier, ProjectUpdate, remote_client::RemoteClient}, zip::ZipBlob};
+use remote_test::{artifacts, capsule::CapsuleChoice, client_errors::ClientError, limits::ResourceLimits, manifest::Manifest, pb::{ArtifactRequest, CapsuleOptions, ListProjectsRequest, ListRunsRequest, OutputRequest, OutputStream, Phase, Project, ProjectChunk, ProjectIdentifier, ProjectIncrement, ProjectInfo, ResourceLimit, RunHistoryRequest, RunIdentifier, RunRequest, RunState, TestDefinition, TestFilter, TestResult, TestResults, TestStatus, UpdateResponse, UploadIdentifier, remote_client::RemoteClient, test_event::Event}, zip::{ZipBlob, parse_patterns}};
 use serde::{Serialize, Deserialize};
 
+/// Test given either as plain command or with name and tags for filtering
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TestConfig {
+    Command(String),
+    Detailed {
+        command: String,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        tags: Vec<String>,
+        /// Test may run at the same time as other parallel tests
+        #[serde(default)]
+        parallel: bool,
+        /// Variables set for this test, on top of the project's
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        /// Times the test is run again after failing
+        #[serde(default)]
+        retries: u32,
+    },
+}
+
+impl TestConfig {
+    fn command(&self) -> &str {
+        match self {
+            TestConfig::Command(command) => command,
+            TestConfig::Detailed { command, .. } => command,
+        }
+    }
+}
+
+impl From<&TestConfig> for TestDefinition {
+    fn from(test: &TestConfig) -> Self {
+        match test {
+            TestConfig::Command(command) => TestDefinition { command: command.clone(), ..Default::default() },
+            TestConfig::Detailed { command, name, tags, parallel, env, retries } => TestDefinition {
+                command: command.clone(),
+                name: name.clone(),
+                tags: tags.clone(),
+                parallel: *parallel,
+                env: env.clone().into_iter().collect(),
+                retries: *retries,
+            },
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ProjectConfig {
     pub name: String,
-    pub tests: Vec<String>,
+    pub tests: Vec<TestConfig>,
     pub exclude: Vec<String>,
+    /// Seconds a single test may run on the server
+    #[serde(default)]
+    pub test_timeout: Option<u64>,
+    /// Seconds all tests of a run may take together
+    #[serde(default)]
+    pub run_timeout: Option<u64>,
+    /// Number of parallel tests run at the same time on the server
+    #[serde(default)]
+    pub max_parallel: Option<u32>,
+    /// Variables set for all tests
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Tests don't inherit the server's environment
+    #[serde(default)]
+    pub clear_env: bool,
+    /// Server variables passed to tests even if the environment is cleared
+    #[serde(default)]
+    pub pass_env: Vec<String>,
+    /// Commands run before the tests, tests are skipped if one fails
+    #[serde(default)]
+    pub setup: Vec<String>,
+    /// Commands run after the tests, no matter how they ended
+    #[serde(default)]
+    pub teardown: Vec<String>,
+    /// Capsule tests are run in, server default if unset
+    #[serde(default)]
+    pub capsule: Option<CapsuleChoice>,
+    /// KB of output kept from start and end of each test output stream,
+    /// server default if unset, 0 keeps all output
+    #[serde(default)]
+    pub output_limit_kb: Option<u64>,
+    /// Resources each test command may use on the server
+    #[serde(default)]
+    pub limits: Option<ResourceLimits>,
+    /// Glob patterns of files collected on the server after each run
+    #[serde(default)]
+    pub artifacts: Vec<String>,
 }
 
 impl From<&ProjectConfig> for Project {
     fn from(conf: &ProjectConfig) -> Self {
-        Project { name: conf.name.clone(), tests: conf.tests.clone() }
+        Project {
+            name: conf.name.clone(),
+            tests: conf.tests.iter().map(|t| t.command().to_string()).collect(),
+            test_definitions: conf.tests.iter().map(TestDefinition::from).collect(),
+            test_timeout: conf.test_timeout,
+            run_timeout: conf.run_timeout,
+            max_parallel: conf.max_parallel,
+            env: conf.env.clone().into_iter().collect(),
+            clear_env: conf.clear_env,
+            pass_env: conf.pass_env.clone(),
+            setup: conf.setup.clone(),
+            teardown: conf.teardown.clone(),
+            capsule: conf.capsule.clone().map(CapsuleOptions::from),
+            output_limit_kb: conf.output_limit_kb,
+            limits: conf.limits.map(Into::into),
+            artifacts: conf.artifacts.clone(),
+        }
     }
 }
 
@@ -22,6 +123,116 @@ impl From<&ProjectConfig> for ProjectIdentifier {
     }
 }
 
+/// Parses test selectors separated by whitespace
+/// Numbers select tests by position, `tag:<tag>` by tag, `re:<regex>` by
+/// command line and anything else by name
+fn parse_filter(arg: &str) -> Result<Option<TestFilter>, String> {
+    let mut filter = TestFilter::default();
+    for token in arg.split_whitespace() {
+        if let Ok(n) = token.parse::<u32>() {
+            if n == 0 {
+                return Err(String::from("Test numbers start at 1"));
+            }
+            filter.indices.push(n - 1);
+        } else if let Some(tag) = token.strip_prefix("tag:") {
+            filter.tags.push(tag.to_string());
+        } else if let Some(pattern) = token.strip_prefix("re:") {
+            if filter.pattern.is_some() {
+                return Err(String::from("Only one pattern can be given"));
+            }
+            regex::Regex::new(pattern).map_err(|e| format!("Invalid test pattern: {}", e))?;
+            filter.pattern = Some(pattern.to_string());
+        } else {
+            filter.names.push(token.to_string());
+        }
+    }
+    if arg.trim().is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(filter))
+    }
+}
+
+/// Parses `[shuffle] [seed:<n>] [filter]` into a run request
+fn run_request(conf: &ProjectConfig, arg: &str) -> Result<RunRequest, ClientError> {
+    let mut request = RunRequest { name: conf.name.clone(), ..Default::default() };
+    let mut rest = arg.trim();
+    // Options come before the filter
+    loop {
+        let (token, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+        if token == "shuffle" {
+            request.shuffle = true;
+        } else if let Some(seed) = token.strip_prefix("seed:") {
+            let seed = seed.parse::<u64>()
+                .map_err(|_| ClientError::local(format!("Invalid seed '{}'", seed)))?;
+            request.seed = Some(seed);
+        } else {
+            break;
+        }
+        rest = tail.trim_start();
+    }
+    request.filter = parse_filter(rest)
+        .map_err(ClientError::local)?;
+    Ok(request)
+}
+
+/// Parses `<n> [until-failure] [options] [filter]` into a request repeating
+/// tests
+fn stress_request(conf: &ProjectConfig, arg: &str) -> Result<RunRequest, ClientError> {
+    let usage = "Usage: stress <n> [until-failure] [shuffle] [seed:<n>] [filter]";
+    let (times, rest) = arg.split_once(' ').unwrap_or((arg, ""));
+    let times = times.parse::<u32>()
+        .ok()
+        .filter(|n| *n > 0)
+        .ok_or_else(|| ClientError::local(usage))?;
+    let rest = rest.trim();
+    let (until_failure, filter) = match rest.strip_prefix("until-failure") {
+        Some(filter) if filter.is_empty() || filter.starts_with(' ') => (true, filter),
+        _ => (false, rest),
+    };
+    let mut request = run_request(conf, filter)?;
+    request.repeat = Some(times);
+    request.until_failure = until_failure;
+    Ok(request)
+}
+
+/// Project state last sent to the server, base for incremental updates
+#[derive(Serialize, Deserialize)]
+pub struct UploadState {
+    pub hash: String,
+    pub manifest: Manifest,
+}
+
+fn state_file() -> String {
+    std::env::var("PROJECT_STATE").unwrap_or(String::from(".rt-state.json"))
+}
+
+/// Directory downloaded artifacts are stored in, one subdirectory per run
+static ARTIFACT_DIR: &str = ".rt-artifacts";
+
+/// Exclude patterns from project config plus the local state file and
+/// downloaded artifacts
+fn exclude_patterns(conf: &ProjectConfig) -> Vec<String> {
+    let mut exclude = conf.exclude.clone();
+    let state = Path::new(".").join(state_file());
+    exclude.push(format!("^{}$", regex::escape(state.to_string_lossy().as_ref())));
+    let artifacts = Path::new(".").join(ARTIFACT_DIR);
+    exclude.push(format!("^{}/", regex::escape(artifacts.to_string_lossy().as_ref())));
+    exclude
+}
+
+fn read_upload_state() -> Result<UploadState, Box<dyn Error>> {
+    let file = std::fs::File::open(state_file())?;
+    let state: UploadState = serde_json::from_reader(file)?;
+    Ok(state)
+}
+
+fn write_upload_state(state: &UploadState) -> Result<(), Box<dyn Error>> {
+    let file = std::fs::File::create(state_file())?;
+    serde_json::to_writer(file, state)?;
+    Ok(())
+}
+
 /// Reads project config struct from json config file
 fn read_project_config(path: impl AsRef<Path>) -> Result<ProjectConfig, Box<dyn Error>> {
     let file = std::fs::File::open(path)?;
@@ -32,10 +243,10 @@ fn read_project_config(path: impl AsRef<Path>) -> Result<ProjectConfig, Box<dyn
 async fn register_project(dest: String, conf: &ProjectConfig) -> Result<String, ClientError> {
     let mut client = RemoteClient::connect(dest)
         .await
-        .map_err(|e| ClientError::failed_connect(e))?;
+        .map_err(ClientError::failed_connect)?;
     let res = client.register_project(Project::from(conf))
         .await
-        .map_err(|e| ClientError::remote(e))?
+        .map_err(ClientError::remote)?
         .into_inner();
     let msg;
     if res.success {
@@ -48,13 +259,32 @@ async fn register_project(dest: String, conf: &ProjectConfig) -> Result<String,
     Ok(msg)
 }
 
+async fn configure_project(dest: String, conf: &ProjectConfig) -> Result<String, ClientError> {
+    let mut client = RemoteClient::connect(dest)
+        .await
+        .map_err(ClientError::failed_connect)?;
+    let res = client.update_project_config(Project::from(conf))
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner();
+    let msg;
+    if res.success {
+        msg = format!("Successfully updated config of project {}", conf.name.as_str());
+    } else if res.error.is_some() {
+        msg = format!("Project config could not be updated: {}", res.error.unwrap().as_str());
+    } else {
+        msg = String::from("Project config could not be updated");
+    }
+    Ok(msg)
+}
+
 async fn unregister_project(dest: String, conf: &ProjectConfig) -> Result<String, ClientError> {
     let mut client = RemoteClient::connect(dest)
         .await
-        .map_err(|e| ClientError::failed_connect(e))?;
+        .map_err(ClientError::failed_connect)?;
     let res = client.unregister_project(ProjectIdentifier::from(conf))
         .await
-        .map_err(|e| ClientError::remote(e))?
+        .map_err(ClientError::remote)?
         .into_inner();
     let msg;
     if res.success {
@@ -71,26 +301,134 @@ async fn unregister_project(dest: String, conf: &ProjectConfig) -> Result<String
     Ok(msg)
 }
 
-async fn update_project(dest: String, conf: &ProjectConfig) -> Result<String, ClientError> {
+/// Size of chunks sent with chunked uploads
+const CHUNK_SIZE: usize = 1024 * 1024;
+/// Number of attempts before giving up on an interrupted upload
+const UPLOAD_ATTEMPTS: usize = 5;
+
+/// Sends blob in chunks, resuming interrupted uploads where the server left off
+async fn upload_blob(dest: String, name: &str, hash: &str, blob: Vec<u8>) -> Result<UpdateResponse, ClientError> {
+    let blob = Arc::new(blob);
+    let mut attempt = 1;
+    loop {
+        match upload_chunks(dest.clone(), name, hash, blob.clone()).await {
+            Err(e) if attempt < UPLOAD_ATTEMPTS => {
+                println!("\nUpload interrupted ({}), resuming", e);
+                attempt += 1;
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            },
+            res => return res,
+        }
+    }
+}
+
+async fn upload_chunks(dest: String, name: &str, hash: &str, blob: Arc<Vec<u8>>) -> Result<UpdateResponse, ClientError> {
     let mut client = RemoteClient::connect(dest)
         .await
-        .map_err(|e| ClientError::failed_connect(e))?;
-    let (hash, blob) = {
-        let mut zip = ZipBlob::new(conf.exclude.clone())
-            .map_err(|e| ClientError::local(e))?;
+        .map_err(ClientError::failed_connect)?;
+    let ident = UploadIdentifier { name: name.to_string(), hash: hash.to_string() };
+    let received = client.get_upload_status(ident)
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner()
+        .received;
+    let size = blob.len() as u64;
+    let offset = received.min(size);
+    let (name, hash) = (name.to_string(), hash.to_string());
+    // Send at least one chunk, so the server completes an already received upload
+    let chunks = (offset..size.max(offset + 1))
+        .step_by(CHUNK_SIZE)
+        .map(move |start| {
+            let end = (start as usize + CHUNK_SIZE).min(blob.len());
+            ProjectChunk {
+                name: name.clone(),
+                hash: hash.clone(),
+                size,
+                offset: start,
+                data: blob[start as usize..end].to_vec(),
+            }
+        });
+    let res = client.upload_project(tokio_stream::iter(chunks))
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner();
+    Ok(res)
+}
+
+async fn update_project(dest: String, conf: &ProjectConfig) -> Result<String, ClientError> {
+    let (hash, blob, manifest) = {
+        let mut zip = ZipBlob::new(exclude_patterns(conf))
+            .map_err(ClientError::local)?;
         zip.add_dir(".").await
-            .map_err(|e| ClientError::local(e))?;
-        zip.finish().await
-            .map_err(|e| ClientError::local(e))?
+            .map_err(ClientError::local)?;
+        let manifest = zip.manifest().clone();
+        let (hash, blob) = zip.finish().await
+            .map_err(ClientError::local)?;
+        (hash, blob, manifest)
     };
-    let update = ProjectUpdate { name: conf.name.clone(), hash, blob};
-    let res = client.update_project(update)
+    let res = upload_blob(dest, &conf.name, &hash, blob).await?;
+    let msg;
+    if res.success {
+        write_upload_state(&UploadState { hash: res.hash.clone(), manifest })
+            .map_err(ClientError::local)?;
+        msg = format!("{}:{} has been successsfully updated", res.project, res.hash);
+    } else if res.error.is_some() {
+        msg = format!("{}:{} could not be updated: {}", res.project, res.hash, res.error.unwrap());
+    } else {
+        msg = format!("{}:{} could not be updated", res.project, res.hash);
+    }
+    Ok(msg)
+}
+
+async fn increment_project(dest: String, conf: &ProjectConfig) -> Result<String, ClientError> {
+    let state = read_upload_state()
+        .map_err(|e| ClientError::local(format!("Could not read {}, run 'init' first: {}", state_file(), e)))?;
+    let exclude = parse_patterns(exclude_patterns(conf))
+        .map_err(ClientError::local)?;
+    let manifest = Manifest::from_dir(".", &exclude)
+        .await
+        .map_err(ClientError::local)?;
+    let (changed, deleted) = state.manifest.diff(&manifest);
+    if changed.is_empty() && deleted.is_empty() {
+        return Ok(format!("{}:{} is up to date", conf.name, state.hash));
+    }
+    let newhash = manifest.tree_hash().await;
+    let blob = {
+        let mut zip = ZipBlob::new(Vec::new())
+            .map_err(ClientError::local)?;
+        println!("\n*** Compressing changed files");
+        for path in changed.iter() {
+            zip.add_file(Path::new(path.as_str())).await
+                .map_err(ClientError::local)?;
+        }
+        let (_, blob) = zip.finish().await
+            .map_err(ClientError::local)?;
+        blob
+    };
+    for path in deleted.iter() {
+        println!("\t{} (deleted)", path);
+    }
+    let (n_changed, n_deleted) = (changed.len(), deleted.len());
+
+    let mut client = RemoteClient::connect(dest)
         .await
-        .map_err(|e| ClientError::remote(e))?
+        .map_err(ClientError::failed_connect)?;
+    let increment = ProjectIncrement {
+        name: conf.name.clone(),
+        prevhash: state.hash,
+        newhash,
+        blob,
+        deleted,
+    };
+    let res = client.increment_project(increment)
+        .await
+        .map_err(ClientError::remote)?
         .into_inner();
     let msg;
     if res.success {
-        msg = format!("{}:{} has been successsfully updated", res.project, res.hash);
+        write_upload_state(&UploadState { hash: res.hash.clone(), manifest })
+            .map_err(ClientError::local)?;
+        msg = format!("{}:{} has been successsfully updated ({} changed, {} deleted)", res.project, res.hash, n_changed, n_deleted);
     } else if res.error.is_some() {
         msg = format!("{}:{} could not be updated: {}", res.project, res.hash, res.error.unwrap());
     } else {
@@ -103,50 +441,631 @@ fn success_to_str(success: bool) -> &'static str {
     if success { "OK" } else { "Failed" }
 }
 
-async fn run_tests(dest: String, conf: &ProjectConfig) -> Result<String, ClientError> {
+fn status_to_str(result: &TestResult) -> &'static str {
+    match TestStatus::from_i32(result.status) {
+        Some(TestStatus::TimedOut) => "Timed out",
+        Some(TestStatus::Skipped) => "Skipped",
+        Some(TestStatus::Cancelled) => "Cancelled",
+        Some(TestStatus::SetupFailed) => "Setup failed",
+        Some(TestStatus::Error) => "Error",
+        Some(TestStatus::LimitExceeded) => "Limit exceeded",
+        Some(TestStatus::Flaky) => "Flaky",
+        _ => success_to_str(result.success),
+    }
+}
+
+fn signal_name(signal: i32) -> &'static str {
+    match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        _ => "unknown signal",
+    }
+}
+
+fn phase_to_str(phase: i32) -> &'static str {
+    match Phase::from_i32(phase) {
+        Some(Phase::Setup) => "Setup",
+        Some(Phase::Teardown) => "Teardown",
+        _ => "Test",
+    }
+}
+
+/// Describes how a test process ended and how long it took
+fn result_details(result: &TestResult) -> String {
+    let end = match (result.exit_code, result.signal) {
+        (Some(code), _) => format!("exit code {}", code),
+        (None, Some(signal)) => format!("signal {} ({})", signal, signal_name(signal)),
+        (None, None) => String::from("no exit status"),
+    };
+    let limit = match ResourceLimit::from_i32(result.exceeded_limit) {
+        Some(ResourceLimit::CpuTime) => ", cpu time limit",
+        Some(ResourceLimit::Memory) => ", memory limit",
+        Some(ResourceLimit::Processes) => ", process limit",
+        _ => "",
+    };
+    format!("{}, {:.3}s{}", end, result.duration_ms as f64 / 1000.0, limit)
+}
+
+/// Runs tests, rendering their progress
+/// Output is streamed as it arrives if live, otherwise only output of failed
+/// tests is shown once they have ended.
+async fn run_tests(dest: String, request: RunRequest, live: bool) -> Result<String, ClientError> {
+    use std::io::Write;
     let mut client = RemoteClient::connect(dest)
         .await
-        .map_err(|e| ClientError::failed_connect(e))?;
-    let res = client.run_tests(ProjectIdentifier::from(conf))
+        .map_err(ClientError::failed_connect)?;
+    let mut stream = client.stream_tests(request)
         .await
-        .map_err(|e| ClientError::remote(e))?
+        .map_err(ClientError::remote)?
         .into_inner();
 
-    let all_successful = res.results.iter()
+    // Render events as they arrive
+    let mut results = None;
+    while let Some(event) = stream.message().await.map_err(ClientError::remote)? {
+        match event.event {
+            Some(Event::Started(_)) | Some(Event::Output(_)) if !live => (),
+            Some(Event::Finished(finished)) if !live => {
+                if let Some(result) = finished.result.filter(|r| !r.success) {
+                    println!("{}{} {} {} {} ({})", iteration_label(&result, finished.phase == Phase::Test as i32), phase_to_str(finished.phase), finished.index + 1, "*".repeat(16), status_to_str(&result), result_details(&result));
+                    let _ = std::io::stdout().write_all(&result.stdout);
+                    let _ = std::io::stderr().write_all(&result.stderr);
+                }
+            },
+            Some(Event::Started(started)) => {
+                let retry = if started.attempt > 0 { format!(" (retry {})", started.attempt) } else { String::new() };
+                println!("{} {}{} {} {}", phase_to_str(started.phase), started.index + 1, retry, "*".repeat(16), started.command);
+            },
+            Some(Event::Output(chunk)) => {
+                if chunk.stream == OutputStream::Stderr as i32 {
+                    let _ = std::io::stderr().write_all(&chunk.data);
+                } else {
+                    let _ = std::io::stdout().write_all(&chunk.data);
+                    let _ = std::io::stdout().flush();
+                }
+            },
+            Some(Event::Finished(finished)) => {
+                if let Some(result) = finished.result {
+                    println!("{} {} {} {} ({})", phase_to_str(finished.phase), finished.index + 1, "*".repeat(16), status_to_str(&result), result_details(&result));
+                    // Command never ran, so its error was not streamed
+                    if result.status == TestStatus::Error as i32 {
+                        println!("{}", String::from_utf8_lossy(&result.stderr));
+                    }
+                }
+            },
+            Some(Event::Results(res)) => results = Some(res),
+            None => (),
+        }
+    }
+    let res = results.ok_or_else(|| ClientError::remote("Test run ended without results"))?;
+
+    let mut lines = vec!["*".repeat(32)];
+    lines.append(&mut report_lines(&res, false));
+    Ok(lines.join("\n"))
+}
+
+/// Formats test results, optionally including test output
+fn report_lines(res: &TestResults, with_output: bool) -> Vec<String> {
+    let all_successful = res.setup.iter()
+        .chain(res.results.iter())
+        .chain(res.teardown.iter())
         .all(|x| x.success);
     let mut lines: Vec<String> = Vec::new();
-    lines.push(format!("Test results for {}:{}", res.name, res.hash));
+    lines.push(format!("Test results for {}:{} (run {})", res.name, res.hash, res.run_id));
     lines.push(format!(" started at {}", res.timestamp));
-    for (i, result) in res.results.into_iter().enumerate() {
-        let n = i + 1;
-        lines.push(format!("Test {} {} {}", 
-            n,
-            "*".repeat(16),
-            success_to_str(result.success)
+    if let Some(seed) = res.seed {
+        lines.push(format!(" shuffled with seed:{}", seed));
+    }
+    // Only failures of repeated tests are listed, after their summary
+    if !res.stats.is_empty() {
+        lines.push(format!(" {} iterations", res.iterations));
+    }
+    for stats in res.stats.iter() {
+        let flaky = if stats.flaky > 0 { format!(", {} flaky", stats.flaky) } else { String::new() };
+        lines.push(format!("  Test {}{} passed {}/{} ({:.1}%){}",
+            stats.index + 1,
+            stats.name.as_ref().map(|n| format!(" ({})", n)).unwrap_or_default(),
+            stats.passed,
+            stats.runs,
+            stats.passed as f64 * 100.0 / stats.runs.max(1) as f64,
+            flaky,
         ));
-        let stdout = String::from_utf8_lossy(&result.stdout);
-        let stderr = String::from_utf8_lossy(&result.stderr);
-        lines.push(format!("\tstdout: \"{}\"", stdout));
-        lines.push(format!("\tstderr: \"{}\"", stderr));
     }
-    lines.push(format!("{}", "*".repeat(32)));
+    let tests: Vec<TestResult> = res.results.iter()
+        .filter(|r| res.stats.is_empty() || !r.success)
+        .cloned()
+        .collect();
+    let phases = [(Phase::Setup, &res.setup), (Phase::Test, &tests), (Phase::Teardown, &res.teardown)];
+    for (phase, results) in phases {
+        for result in results.iter() {
+            lines.push(format!("  {}{} {}{} {} started {}, {}",
+                iteration_label(result, phase == Phase::Test && !res.stats.is_empty()),
+                phase_to_str(phase as i32),
+                result.index + 1,
+                result.name.as_ref().map(|n| format!(" ({})", n)).unwrap_or_default(),
+                status_to_str(result),
+                result.started_at,
+                result_details(result),
+            ));
+            output_lines(&mut lines, &res.run_id, phase, result, result.attempts.len() as u32, with_output);
+            // Failed earlier attempts of retried tests
+            for (n, attempt) in result.attempts.iter().enumerate() {
+                lines.push(format!("\tattempt {} {} started {}, {}",
+                    n + 1,
+                    status_to_str(attempt),
+                    attempt.started_at,
+                    result_details(attempt),
+                ));
+                output_lines(&mut lines, &res.run_id, phase, attempt, n as u32, with_output);
+            }
+        }
+    }
+    if !res.artifacts.is_empty() {
+        lines.push(format!("  {} artifacts collected, see 'artifacts {}'", res.artifacts.len(), res.run_id));
+    }
     lines.push(format!("  Tests successful {} {}",
         "*".repeat(5),
         success_to_str(all_successful)
     ));
+    lines
+}
+
+/// Names the iteration result was run in, if tests were repeated
+fn iteration_label(result: &TestResult, repeated: bool) -> String {
+    if repeated {
+        format!("Iteration {} ", result.iteration + 1)
+    } else {
+        String::new()
+    }
+}
+
+/// Adds output of attempt of result, or a hint how to get it if truncated
+fn output_lines(lines: &mut Vec<String>, run_id: &str, phase: Phase, result: &TestResult, attempt: u32, with_output: bool) {
+    if result.truncated {
+        lines.push(format!("\toutput truncated from {} bytes stdout, {} bytes stderr, see 'output {} {}'",
+            result.stdout_size,
+            result.stderr_size,
+            run_id,
+            output_selector(phase, result.index, result.iteration, attempt),
+        ));
+    }
+    if with_output {
+        lines.push(format!("\tcommand: {}", result.command));
+        lines.push(format!("\tstdout: \"{}\"", String::from_utf8_lossy(&result.stdout)));
+        lines.push(format!("\tstderr: \"{}\"", String::from_utf8_lossy(&result.stderr)));
+    }
+}
+
+/// Selects a command in arguments of the output command
+fn output_selector(phase: Phase, index: u32, iteration: u32, attempt: u32) -> String {
+    let iteration = if iteration > 0 { format!(" iteration:{}", iteration + 1) } else { String::new() };
+    let attempt = if attempt > 0 { format!(" attempt:{}", attempt + 1) } else { String::new() };
+    match phase {
+        Phase::Test => format!("{}{}{}", index + 1, iteration, attempt),
+        Phase::Setup => format!("setup {}", index + 1),
+        Phase::Teardown => format!("teardown {}", index + 1),
+    }
+}
+
+/// Parses `<run> [setup|teardown] <number> [iteration:<n>] [attempt:<n>]
+/// [stderr]` into an output request
+fn parse_output_request(arg: &str) -> Result<OutputRequest, String> {
+    let usage = "Usage: output <run> [setup|teardown] <number> [iteration:<n>] [attempt:<n>] [stderr]";
+    let mut tokens = arg.split_whitespace().peekable();
+    let run_id = tokens.next().ok_or(usage)?.to_string();
+    let phase = match tokens.peek() {
+        Some(&"setup") => Phase::Setup,
+        Some(&"teardown") => Phase::Teardown,
+        _ => Phase::Test,
+    };
+    if phase != Phase::Test {
+        tokens.next();
+    }
+    let index = match tokens.next().and_then(|n| n.parse::<u32>().ok()) {
+        Some(0) => return Err(String::from("Command numbers start at 1")),
+        Some(n) => n - 1,
+        None => return Err(String::from(usage)),
+    };
+    let (mut iteration, mut attempt) = (0, 0);
+    let mut stream = OutputStream::Stdout;
+    for token in tokens {
+        match token {
+            "stdout" => stream = OutputStream::Stdout,
+            "stderr" => stream = OutputStream::Stderr,
+            _ => {
+                let (key, n) = token.split_once(':').ok_or(usage)?;
+                let n = match n.parse::<u32>() {
+                    Ok(0) => return Err(format!("{} numbers start at 1", key)),
+                    Ok(n) => n - 1,
+                    Err(_) => return Err(String::from(usage)),
+                };
+                match key {
+                    "iteration" => iteration = n,
+                    "attempt" => attempt = n,
+                    _ => return Err(String::from(usage)),
+                }
+            },
+        }
+    }
+    Ok(OutputRequest {
+        run_id,
+        phase: phase as i32,
+        index,
+        stream: stream as i32,
+        attempt,
+        iteration,
+    })
+}
+
+/// Prints complete output of a command as it is received
+async fn get_output(dest: String, arg: &str) -> Result<String, ClientError> {
+    use std::io::Write;
+    let request = parse_output_request(arg)
+        .map_err(ClientError::local)?;
+    let mut client = RemoteClient::connect(dest)
+        .await
+        .map_err(ClientError::failed_connect)?;
+    let mut stream = client.get_test_output(request)
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner();
+    let mut size = 0;
+    while let Some(chunk) = stream.message().await.map_err(ClientError::remote)? {
+        size += chunk.data.len();
+        let _ = std::io::stdout().write_all(&chunk.data);
+    }
+    let _ = std::io::stdout().flush();
+    Ok(format!("\n{} bytes of output", size))
+}
+
+/// Stores artifacts of a run below the local artifact directory
+/// arg is `<run> [path...]`, all artifacts are fetched if no path is given.
+async fn download_artifacts(dest: String, arg: &str) -> Result<String, ClientError> {
+    use std::io::Write;
+    let mut tokens = arg.split_whitespace();
+    let run_id = tokens.next()
+        .ok_or_else(|| ClientError::local("Usage: artifacts <run> [path...]"))?
+        .to_string();
+    let request = ArtifactRequest {
+        run_id: run_id.clone(),
+        paths: tokens.map(String::from).collect(),
+    };
+    let mut client = RemoteClient::connect(dest)
+        .await
+        .map_err(ClientError::failed_connect)?;
+    let mut stream = client.download_artifacts(request)
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner();
+    let dir = Path::new(ARTIFACT_DIR).join(&run_id);
+    let mut file: Option<std::fs::File> = None;
+    let mut count = 0;
+    while let Some(chunk) = stream.message().await.map_err(ClientError::remote)? {
+        if chunk.first {
+            // Server must not place files outside of the run's directory
+            let path = artifacts::artifact_file(&dir, &chunk.path)
+                .ok_or_else(|| ClientError::local(format!("Invalid artifact path '{}'", chunk.path.as_str())))?;
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| ClientError::local(e.to_string()))?;
+            }
+            file = Some(std::fs::File::create(&path).map_err(|e| ClientError::local(e.to_string()))?);
+            println!("\t{}", chunk.path.as_str());
+            count += 1;
+        }
+        if let Some(file) = file.as_mut() {
+            file.write_all(&chunk.data)
+                .map_err(|e| ClientError::local(e.to_string()))?;
+        }
+    }
+    Ok(format!("Stored {} artifacts in {}", count, dir.display()))
+}
+
+fn run_state_to_str(state: i32) -> &'static str {
+    match RunState::from_i32(state) {
+        Some(RunState::Running) => "running",
+        Some(RunState::Finished) => "finished",
+        Some(RunState::Cancelled) => "cancelled",
+        Some(RunState::Failed) => "failed",
+        _ => "unknown",
+    }
+}
+
+async fn start_run(dest: String, conf: &ProjectConfig, filter: &str) -> Result<String, ClientError> {
+    let request = run_request(conf, filter)?;
+    let mut client = RemoteClient::connect(dest)
+        .await
+        .map_err(ClientError::failed_connect)?;
+    let res = client.start_run(request)
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner();
+    Ok(format!("Started run {}", res.run_id))
+}
+
+async fn get_run(dest: String, run_id: String) -> Result<String, ClientError> {
+    let mut client = RemoteClient::connect(dest)
+        .await
+        .map_err(ClientError::failed_connect)?;
+    let info = client.get_run(RunIdentifier { run_id })
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner();
+    let mut lines = vec![format!("Run {} of {}: {}", info.run_id, info.project, run_state_to_str(info.state))];
+    if let Some(finished_at) = info.finished_at {
+        lines.push(format!(" ended at {}", finished_at));
+    }
+    if let Some(error) = info.error {
+        lines.push(format!(" error: {}", error));
+    }
+    if let Some(results) = info.results {
+        lines.append(&mut report_lines(&results, true));
+    }
+    Ok(lines.join("\n"))
+}
+
+async fn list_runs(dest: String, conf: &ProjectConfig) -> Result<String, ClientError> {
+    let mut client = RemoteClient::connect(dest)
+        .await
+        .map_err(ClientError::failed_connect)?;
+    let res = client.list_runs(ListRunsRequest { project: Some(conf.name.clone()) })
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner();
+    if res.runs.is_empty() {
+        return Ok(format!("No runs for {}", conf.name));
+    }
+    let lines: Vec<String> = res.runs.iter()
+        .map(|info| {
+            let results = info.results.as_ref()
+                .map(|r| r.results.as_slice())
+                .unwrap_or_default();
+            let passed = results.iter().filter(|r| r.success).count();
+            format!("  {} {} {} ({}/{} passed)", info.run_id, info.started_at, run_state_to_str(info.state), passed, results.len())
+        })
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+/// Number of runs shown per history page
+const HISTORY_PAGE_SIZE: u32 = 10;
+
+async fn run_history(dest: String, conf: &ProjectConfig, page: &str) -> Result<String, ClientError> {
+    let page = if page.is_empty() {
+        1
+    } else {
+        page.parse::<u32>()
+            .ok()
+            .filter(|p| *p > 0)
+            .ok_or_else(|| ClientError::local(format!("Invalid page number '{}'", page)))?
+    };
+    let mut client = RemoteClient::connect(dest)
+        .await
+        .map_err(ClientError::failed_connect)?;
+    let request = RunHistoryRequest {
+        project: conf.name.clone(),
+        offset: (page - 1) * HISTORY_PAGE_SIZE,
+        limit: Some(HISTORY_PAGE_SIZE),
+        with_output: false,
+    };
+    let res = client.get_run_history(request)
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner();
+    if res.runs.is_empty() {
+        return Ok(format!("No stored runs for {} on page {}", conf.name, page));
+    }
+    let first = (page - 1) * HISTORY_PAGE_SIZE + 1;
+    let mut lines = vec![format!("Runs {}-{} of {} for {}", first, first + res.runs.len() as u32 - 1, res.total, conf.name)];
+    for info in res.runs.iter() {
+        let results = info.results.as_ref()
+            .map(|r| r.results.as_slice())
+            .unwrap_or_default();
+        let hash = info.results.as_ref()
+            .map(|r| r.hash.as_str())
+            .unwrap_or_default();
+        let passed = results.iter().filter(|r| r.success).count();
+        let mut line = format!("  {} {} {}:{} ({}/{} passed)", info.run_id, info.started_at, run_state_to_str(info.state), hash, passed, results.len());
+        // Repeated tests may have failed more than once
+        let failed: BTreeSet<u32> = results.iter()
+            .filter(|r| !r.success)
+            .map(|r| r.index + 1)
+            .collect();
+        let failed: Vec<String> = failed.iter()
+            .map(u32::to_string)
+            .collect();
+        if !failed.is_empty() {
+            line = format!("{} failed: {}", line, failed.join(", "));
+        }
+        lines.push(line);
+    }
     Ok(lines.join("\n"))
 }
 
+fn last_run_str(info: &ProjectInfo) -> String {
+    match &info.last_run {
+        Some(run) => format!("{} {} {} ({}/{} passed)", run.run_id, run.timestamp, run_state_to_str(run.state), run.passed, run.total),
+        None => String::from("never"),
+    }
+}
+
+async fn list_projects(dest: String) -> Result<String, ClientError> {
+    let mut client = RemoteClient::connect(dest)
+        .await
+        .map_err(ClientError::failed_connect)?;
+    let res = client.list_projects(ListProjectsRequest {})
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner();
+    if res.projects.is_empty() {
+        return Ok(String::from("No projects registered"));
+    }
+    let lines: Vec<String> = res.projects.iter()
+        .map(|info| {
+            let name = info.project.as_ref().map(|p| p.name.as_str()).unwrap_or_default();
+            format!("  {}:{} last run: {}", name, info.hash.as_deref().unwrap_or("(empty)"), last_run_str(info))
+        })
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+fn sorted_env(env: &HashMap<String, String>) -> BTreeMap<&String, &String> {
+    env.iter().collect()
+}
+
+async fn get_project(dest: String, conf: &ProjectConfig) -> Result<String, ClientError> {
+    let mut client = RemoteClient::connect(dest)
+        .await
+        .map_err(ClientError::failed_connect)?;
+    let info = client.get_project(ProjectIdentifier::from(conf))
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner();
+    let project = info.project.clone().unwrap_or_default();
+    let mut lines = Vec::new();
+    lines.push(format!("Project {}", project.name));
+    lines.push(format!("  hash: {}", info.hash.as_deref().unwrap_or("(empty)")));
+    lines.push(format!("  last update: {}", info.last_update.as_deref().unwrap_or("never")));
+    lines.push(format!("  last run: {}", last_run_str(&info)));
+    if let Some(t) = project.test_timeout {
+        lines.push(format!("  test timeout: {}s", t));
+    }
+    if let Some(t) = project.run_timeout {
+        lines.push(format!("  run timeout: {}s", t));
+    }
+    if let Some(n) = project.max_parallel {
+        lines.push(format!("  max parallel tests: {}", n));
+    }
+    if let Some(limits) = project.limits {
+        let limits = [
+            limits.cpu_time.map(|n| format!("cpu time {}s", n)),
+            limits.memory_mb.map(|n| format!("memory {} MB", n)),
+            limits.open_files.map(|n| format!("open files {}", n)),
+            limits.processes.map(|n| format!("processes {}", n)),
+        ];
+        let limits: Vec<String> = limits.into_iter().flatten().collect();
+        lines.push(format!("  limits: {}", limits.join(", ")));
+    }
+    match project.output_limit_kb {
+        Some(0) => lines.push(String::from("  output limit: none")),
+        Some(kb) => lines.push(format!("  output limit: {} KB", kb)),
+        None => (),
+    }
+    if !project.artifacts.is_empty() {
+        lines.push(format!("  artifacts: {}", project.artifacts.join(", ")));
+    }
+    if project.clear_env {
+        lines.push(format!("  environment: cleared, passing [{}]", project.pass_env.join(", ")));
+    }
+    for (k, v) in sorted_env(&project.env) {
+        lines.push(format!("  env {}={}", k, v));
+    }
+    match project.capsule.and_then(|c| c.kind).map(CapsuleChoice::from) {
+        Some(CapsuleChoice::Transparent) => lines.push(String::from("  capsule: transparent")),
+        Some(CapsuleChoice::Kubernetes(options)) => lines.push(format!("  capsule: kubernetes {}", options.as_args_str().join(" "))),
+        Some(CapsuleChoice::Container(options)) => lines.push(format!("  capsule: container {}", options.as_args_str().join(" "))),
+        Some(CapsuleChoice::Sandbox(options)) => lines.push(format!("  capsule: sandbox {}", options.as_args_str().join(" "))),
+        None => lines.push(String::from("  capsule: server default")),
+    }
+    for (i, command) in project.setup.iter().enumerate() {
+        lines.push(format!("  setup {}: {}", i + 1, command));
+    }
+    for (i, command) in project.teardown.iter().enumerate() {
+        lines.push(format!("  teardown {}: {}", i + 1, command));
+    }
+    lines.push(String::from("  tests:"));
+    for (i, test) in project.test_definitions.iter().enumerate() {
+        let mut line = format!("    {}: {}", i + 1, test.command);
+        if let Some(name) = &test.name {
+            line = format!("{} (name: {})", line, name);
+        }
+        if !test.tags.is_empty() {
+            line = format!("{} (tags: {})", line, test.tags.join(", "));
+        }
+        if test.parallel {
+            line = format!("{} (parallel)", line);
+        }
+        if test.retries > 0 {
+            line = format!("{} (retries: {})", line, test.retries);
+        }
+        if !test.env.is_empty() {
+            let env: Vec<String> = sorted_env(&test.env).into_iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            line = format!("{} (env: {})", line, env.join(" "));
+        }
+        lines.push(line);
+    }
+    Ok(lines.join("\n"))
+}
+
+async fn cancel_run(dest: String, run_id: String) -> Result<String, ClientError> {
+    let mut client = RemoteClient::connect(dest)
+        .await
+        .map_err(ClientError::failed_connect)?;
+    let res = client.cancel_run(RunIdentifier { run_id: run_id.clone() })
+        .await
+        .map_err(ClientError::remote)?
+        .into_inner();
+    let msg;
+    if res.success {
+        msg = format!("Cancelled run {}", run_id);
+    } else if res.error.is_some() {
+        msg = format!("Run could not be cancelled: {}", res.error.unwrap());
+    } else {
+        msg = String::from("Run could not be cancelled");
+    }
+    Ok(msg)
+}
+
 fn help() {
     println!("Commands:");
     println!("  register\tRegister this project at our target server");
     println!("  unregister\tUnregister (remove) this project at our target server");
+    println!("  configure\tReload project config file and send it to our target server");
     println!("  init\tUpdate inital project resources at our target server");
-    println!("  run\tRun tests at the remote server");
+    println!("  update\tSend changes since the last init/update to our target server");
+    println!("  run [shuffle] [seed:<n>] [filter]\tRun tests at the remote server");
+    println!("  start [shuffle] [seed:<n>] [filter]\tStart test run in the background at the remote server");
+    println!("    filter selects tests by number, name, tag:<tag> or re:<regex> (any match)");
+    println!("    shuffle runs tests in random order, seed:<n> repeats the order of an earlier run");
+    println!("  runs\tList test runs of this project");
+    println!("  status <run>\tShow state and results of a test run");
+    println!("  cancel <run>\tStop a running test run");
+    println!("  history [page]\tList stored test runs of this project, newest first");
+    println!("  stress <n> [until-failure] [shuffle] [seed:<n>] [filter]\tRun tests n times in a row, showing only failures");
+    println!("  output <run> [setup|teardown] <number> [iteration:<n>] [attempt:<n>] [stderr]\tShow complete output of a command");
+    println!("  artifacts <run> [path...]\tDownload artifacts collected after a test run");
+    println!("  projects\tList all projects registered at our target server");
+    println!("  info\tShow this project's state at our target server");
     println!("  quit\tExit the program");
     println!("  help\tDisplays this text");
 }
 
+fn print_outcome(result: Result<String, ClientError>) {
+    match result {
+        Ok(s) => println!("{}", s),
+        Err(e) => {
+            println!("Operation failed - {}", e);
+            if let Some(source) = e.source() {
+                println!("  Cause: {}", source);
+            }
+        },
+    }
+    println!();
+}
+
 async fn print_result<Fut>(res: Fut)
     where Fut: Future<Output = Result<String, ClientError>>
 {
@@ -154,10 +1073,7 @@ async fn print_result<Fut>(res: Fut)
         use std::io::Write;
         loop {
             // Print dots in half second intervals
-            let now = Instant::now();
-            while now.elapsed() < Duration::from_millis(500) {
-                tokio::task::yield_now().await;
-            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
             print!(".");
             std::io::stdout().flush().unwrap();
         }
@@ -165,25 +1081,16 @@ async fn print_result<Fut>(res: Fut)
     // Wait until task is ready, then abort dot-print task
     let result = res.await;
     join.abort();
-    println!("");
+    println!();
     // Print result message
-    match result {
-        Ok(s) => println!("{}", s),
-        Err(e) => {
-            println!("Operation failed - {}", e.to_string());
-            if let Some(source) = e.source() {
-                println!("  Cause: {}", source);
-            }
-        },
-    }
-    println!("");
+    print_outcome(result);
 }
 
 #[tokio::main]
 async fn main() {
     let config_file = std::env::var("PROJECT_CONFIG").unwrap_or(String::from(".rt-conf.json"));
-    let conf = read_project_config(config_file).expect("Could not read project config file");
-    let dest = std::env::args().skip(1).next().expect("You need to provide the destination host as argument");
+    let mut conf = read_project_config(&config_file).expect("Could not read project config file");
+    let dest = std::env::args().nth(1).expect("You need to provide the destination host as argument");
 
     println!("### remote-test client {} ###", env!("CARGO_PKG_VERSION"));
     use std::io::Write;
@@ -192,17 +1099,59 @@ async fn main() {
         buf.clear();
         print!("{}_> ", &conf.name);
         std::io::stdout().flush().unwrap();
-        let _n = std::io::stdin().read_line(&mut buf).unwrap();
+        let n = std::io::stdin().read_line(&mut buf).unwrap();
+        if n == 0 {
+            // stdin was closed
+            break;
+        }
 
-        // Get input cmd
-        match buf.as_str().trim_end_matches('\n') {
+        // Get input cmd and its argument
+        let line = buf.trim();
+        let (cmd, arg) = line.split_once(' ')
+            .map(|(c, a)| (c, a.trim().to_string()))
+            .unwrap_or((line, String::new()));
+        match cmd {
             "register" => print_result(register_project(dest.clone(), &conf))
                 .await,
             "unregister" => print_result(unregister_project(dest.clone(), &conf))
                 .await,
+            "configure" => match read_project_config(&config_file) {
+                Ok(c) => {
+                    conf = c;
+                    print_result(configure_project(dest.clone(), &conf)).await
+                },
+                Err(e) => println!("Could not read project config file: {}\n", e),
+            },
             "init" => print_result(update_project(dest.clone(), &conf))
                 .await,
-            "run" => print_result(run_tests(dest.clone(), &conf))
+            "update" => print_result(increment_project(dest.clone(), &conf))
+                .await,
+            // Test output is rendered live, no progress dots needed
+            "run" => match run_request(&conf, &arg) {
+                Ok(request) => print_outcome(run_tests(dest.clone(), request, true).await),
+                Err(e) => print_outcome(Err(e)),
+            },
+            "stress" => match stress_request(&conf, &arg) {
+                Ok(request) => print_outcome(run_tests(dest.clone(), request, false).await),
+                Err(e) => print_outcome(Err(e)),
+            },
+            "start" => print_result(start_run(dest.clone(), &conf, &arg))
+                .await,
+            "runs" => print_result(list_runs(dest.clone(), &conf))
+                .await,
+            "status" if !arg.is_empty() => print_result(get_run(dest.clone(), arg))
+                .await,
+            "cancel" if !arg.is_empty() => print_result(cancel_run(dest.clone(), arg))
+                .await,
+            "history" => print_result(run_history(dest.clone(), &conf, &arg))
+                .await,
+            // Output is printed as it arrives
+            "output" => print_outcome(get_output(dest.clone(), &arg).await),
+            // Artifacts are listed as they arrive
+            "artifacts" => print_outcome(download_artifacts(dest.clone(), &arg).await),
+            "projects" => print_result(list_projects(dest.clone()))
+                .await,
+            "info" => print_result(get_project(dest.clone(), &conf))
                 .await,
             "help" => help(),
             "quit" => break,
@@ -211,3 +1160,33 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_selectors() {
+        let filter = parse_filter("1 3 tag:slow re:^cargo unit").unwrap().unwrap();
+        assert_eq!(filter.indices, vec![0, 2]);
+        assert_eq!(filter.tags, vec![String::from("slow")]);
+        assert_eq!(filter.pattern, Some(String::from("^cargo")));
+        assert_eq!(filter.names, vec![String::from("unit")]);
+    }
+
+    #[test]
+    fn empty_filter_selects_everything() {
+        assert_eq!(parse_filter("  ").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_test_number_zero() {
+        assert_eq!(parse_filter("0").unwrap_err(), "Test numbers start at 1");
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(parse_filter("re:(").unwrap_err().starts_with("Invalid test pattern"));
+        assert_eq!(parse_filter("re:a re:b").unwrap_err(), "Only one pattern can be given");
+    }
+}
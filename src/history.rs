@@ -0,0 +1,133 @@
+use std::{error::Error, path::{Path, PathBuf}};
+
+use log::debug;
+use prost::Message;
+
+use crate::pb::RunInfo;
+use crate::runs::strip_output;
+
+/// Number of runs returned per page if the request sets no limit
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+/// Largest number of runs returned per page
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Keeps ended test runs on disk, so results and output outlive the server
+///
+/// Each run is stored as encoded `RunInfo` in `<dir>/<project>/<run_id>.pb`,
+/// complete output of its commands is spooled to `<dir>/<project>/<run_id>/`.
+/// Only the newest `limit` runs are kept per project.
+pub struct RunHistory {
+    dir: PathBuf,
+    limit: usize,
+}
+
+/// Sort key of a run id, ids are `<timestamp>-<counter>`
+fn run_order(run_id: &str) -> (String, u64) {
+    match run_id.rsplit_once('-') {
+        Some((timestamp, n)) => (timestamp.to_string(), n.parse().unwrap_or_default()),
+        None => (run_id.to_string(), 0),
+    }
+}
+
+impl RunHistory {
+    pub fn new(dir: PathBuf, limit: usize) -> Self {
+        RunHistory { dir, limit }
+    }
+
+    fn project_dir(&self, project: &str) -> PathBuf {
+        self.dir.join(project)
+    }
+
+    /// Returns directory complete output of the project's runs is spooled to,
+    /// with one subdirectory per run
+    pub fn output_dir(&self, project: &str) -> PathBuf {
+        self.project_dir(project)
+    }
+
+    /// Lists ids of all stored runs of project, newest first
+    async fn run_ids(&self, project: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let dir = self.project_dir(project);
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut ids = Vec::new();
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().map(|e| e == "pb").unwrap_or(false) {
+                if let Some(id) = path.file_stem() {
+                    ids.push(id.to_string_lossy().to_string());
+                }
+            }
+        }
+        ids.sort_by_key(|id| std::cmp::Reverse(run_order(id)));
+        Ok(ids)
+    }
+
+    async fn read(path: &Path) -> Result<RunInfo, Box<dyn Error>> {
+        let data = tokio::fs::read(path).await?;
+        Ok(RunInfo::decode(data.as_slice())?)
+    }
+
+    /// Stores an ended run and forgets the oldest runs beyond the limit
+    pub async fn store(&self, info: &RunInfo) -> Result<(), Box<dyn Error>> {
+        let dir = self.project_dir(&info.project);
+        tokio::fs::create_dir_all(&dir).await?;
+        // Write to tmp file, then swap it in
+        let path = dir.join(format!("{}.pb", info.run_id));
+        let tmp = dir.join(format!("{}.pb.tmp", info.run_id));
+        tokio::fs::write(&tmp, info.encode_to_vec()).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        let ids = self.run_ids(&info.project).await?;
+        for id in ids.iter().skip(self.limit) {
+            debug!("removing run {} of {} from history", id.as_str(), info.project.as_str());
+            tokio::fs::remove_file(dir.join(format!("{}.pb", id))).await?;
+            let output = dir.join(id);
+            if output.is_dir() {
+                tokio::fs::remove_dir_all(output).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a page of stored runs of project, newest first, along with
+    /// the total number of stored runs
+    pub async fn page(&self, project: &str, offset: usize, limit: usize, with_output: bool) -> Result<(Vec<RunInfo>, usize), Box<dyn Error>> {
+        let ids = self.run_ids(project).await?;
+        let dir = self.project_dir(project);
+        let mut runs = Vec::new();
+        for id in ids.iter().skip(offset).take(limit) {
+            let mut info = Self::read(&dir.join(format!("{}.pb", id))).await?;
+            if !with_output {
+                strip_output(&mut info);
+            }
+            runs.push(info);
+        }
+        Ok((runs, ids.len()))
+    }
+
+    /// Looks up a stored run of any project
+    pub async fn get(&self, run_id: &str) -> Result<Option<RunInfo>, Box<dyn Error>> {
+        // Run ids must not point outside of the history directory
+        if !self.dir.is_dir() || run_id.contains(std::path::is_separator) || run_id.starts_with('.') {
+            return Ok(None);
+        }
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path().join(format!("{}.pb", run_id));
+            if path.is_file() {
+                return Ok(Some(Self::read(&path).await?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Removes all stored runs of project
+    pub async fn remove_project(&self, project: &str) -> std::io::Result<()> {
+        let dir = self.project_dir(project);
+        if dir.is_dir() {
+            tokio::fs::remove_dir_all(dir).await?;
+        }
+        Ok(())
+    }
+}
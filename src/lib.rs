@@ -1,9 +1,18 @@
+pub mod artifacts;
 pub mod capsule;
 pub mod client_errors;
+pub mod history;
+pub mod limits;
+pub mod manifest;
+pub mod output;
 pub mod project;
+pub mod runs;
+pub mod sandbox;
 pub mod zip;
 
 pub mod pb {
+    // Pod options are large, capsule options are only passed around rarely
+    #![allow(clippy::large_enum_variant)]
     tonic::include_proto!("grpc.remotetest");
 }
 pub mod hash {
@@ -21,6 +30,22 @@ pub mod hash {
         // Reset hasher after use, trust it's always used this way
         hasher.update(data);
         let res = hasher.finalize_reset();
-        base64::encode_config(res.to_vec(), base64::STANDARD)
+        base64::encode_config(res, base64::STANDARD)
+    }
+
+    /// Hash file content without reading the whole file into memory
+    pub async fn hash_file(path: impl AsRef<std::path::Path>) -> std::io::Result<String> {
+        use tokio::io::AsyncReadExt;
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut hasher = sha2::Sha256::default();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(base64::encode_config(hasher.finalize(), base64::STANDARD))
     }
 }
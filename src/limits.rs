@@ -0,0 +1,209 @@
+use std::{os::unix::io::AsRawFd, path::{Path, PathBuf}, time::Duration};
+
+use log::{debug, warn};
+use serde::{Serialize, Deserialize};
+use tokio::process::Command;
+
+use crate::pb::ResourceLimit;
+use crate::project::TestOutput;
+
+/// Resources a single test command may use
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    /// Seconds of CPU time
+    #[serde(default)]
+    pub cpu_time: Option<u64>,
+    /// MB of address space per process, also memory of all processes of the
+    /// test if run in a cgroup
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    /// Open file descriptors per process
+    #[serde(default)]
+    pub open_files: Option<u64>,
+    /// Processes of the test if run in a cgroup, otherwise processes of the
+    /// server's user, see ResourceLimits::apply_to
+    #[serde(default)]
+    pub processes: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == ResourceLimits::default()
+    }
+
+    /// Applies limits to the process started by cmd
+    /// The process is moved into cgroup before it is executed, if supplied.
+    /// Limits enforced by the cgroup are not set as rlimits.
+    ///
+    /// Without a cgroup, processes fall back to RLIMIT_NPROC. It counts all
+    /// processes of the server's user, including the server itself and
+    /// tests running in parallel, and doesn't apply to root at all. Running
+    /// into it only makes fork fail, which can't be told from the outside.
+    pub fn apply_to(&self, cmd: &mut Command, cgroup: Option<&Cgroup>) {
+        let limits = *self;
+        let procs = cgroup.map(|c| c.procs.as_raw_fd());
+        // Only async-signal-safe calls are allowed between fork and exec
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(fd) = procs {
+                    // Writing 0 moves the writing process
+                    if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) != 1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(secs) = limits.cpu_time {
+                    // SIGXCPU at the soft limit tells us which limit was hit
+                    set_rlimit(libc::RLIMIT_CPU, secs, secs + 1)?;
+                }
+                if let Some(mb) = limits.memory_mb {
+                    set_rlimit(libc::RLIMIT_AS, mb * 1024 * 1024, mb * 1024 * 1024)?;
+                }
+                if let Some(n) = limits.open_files {
+                    set_rlimit(libc::RLIMIT_NOFILE, n, n)?;
+                }
+                if let (Some(n), None) = (limits.processes, procs) {
+                    set_rlimit(libc::RLIMIT_NPROC, n, n)?;
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Returns the limit output's test failed on, as far as it can be told
+    /// Running out of open files or address space only shows in the test's
+    /// own output, so only cpu time, and memory and processes of cgroups are
+    /// detected. cpu_time is the time used by the test, see
+    /// children_cpu_time.
+    pub fn exceeded(&self, output: &TestOutput, cpu_time: Duration, cgroup: Option<&Cgroup>) -> Option<ResourceLimit> {
+        if self.cpu_time.is_some() && output.signal == Some(libc::SIGXCPU) {
+            return Some(ResourceLimit::CpuTime);
+        }
+        if let Some(cgroup) = cgroup {
+            if self.memory_mb.is_some() && cgroup.event_count("memory.events", "oom_kill") > 0 {
+                return Some(ResourceLimit::Memory);
+            }
+            if self.processes.is_some() && cgroup.event_count("pids.events", "max") > 0 {
+                return Some(ResourceLimit::Processes);
+            }
+        }
+        // Tests handling SIGXCPU are killed at the hard limit
+        match self.cpu_time {
+            Some(secs) if output.signal == Some(libc::SIGKILL) && cpu_time >= Duration::from_secs(secs) => Some(ResourceLimit::CpuTime),
+            _ => None,
+        }
+    }
+}
+
+/// Returns CPU time used by all children of the server which were waited
+/// for, along with their waited for descendants
+/// The difference before and after a test is the time used by it, plus the
+/// time of tests which ended meanwhile if tests run in parallel.
+pub fn children_cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) } != 0 {
+        return Duration::ZERO;
+    }
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+fn set_rlimit(resource: libc::__rlimit_resource_t, soft: u64, hard: u64) -> std::io::Result<()> {
+    let limit = libc::rlimit { rlim_cur: soft as libc::rlim_t, rlim_max: hard as libc::rlim_t };
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl From<crate::pb::ResourceLimits> for ResourceLimits {
+    fn from(limits: crate::pb::ResourceLimits) -> Self {
+        ResourceLimits {
+            cpu_time: limits.cpu_time,
+            memory_mb: limits.memory_mb,
+            open_files: limits.open_files,
+            processes: limits.processes,
+        }
+    }
+}
+
+impl From<ResourceLimits> for crate::pb::ResourceLimits {
+    fn from(limits: ResourceLimits) -> Self {
+        crate::pb::ResourceLimits {
+            cpu_time: limits.cpu_time,
+            memory_mb: limits.memory_mb,
+            open_files: limits.open_files,
+            processes: limits.processes,
+        }
+    }
+}
+
+/// Number of attempts to remove a cgroup while its processes are dying
+const CGROUP_REMOVE_ATTEMPTS: u32 = 50;
+
+/// cgroup v2 sub-group holding the processes of a single test
+///
+/// The server needs a delegated cgroup directory with the memory and pids
+/// controllers enabled for its children.
+pub struct Cgroup {
+    path: PathBuf,
+    /// Kept open, so processes can move themselves in before exec
+    procs: std::fs::File,
+}
+
+impl Cgroup {
+    /// Creates group name below root, limiting memory and processes
+    pub fn create(root: &Path, name: &str, limits: &ResourceLimits) -> std::io::Result<Self> {
+        let path = root.join(name);
+        std::fs::create_dir(&path)?;
+        let group = Cgroup {
+            procs: std::fs::OpenOptions::new().write(true).open(path.join("cgroup.procs"))?,
+            path,
+        };
+        if let Some(mb) = limits.memory_mb {
+            group.write("memory.max", &(mb * 1024 * 1024).to_string())?;
+            // Don't let the test escape its limit by swapping
+            if let Err(e) = group.write("memory.swap.max", "0") {
+                debug!("could not disable swap for cgroup {}: {}", group.path.display(), e);
+            }
+        }
+        if let Some(n) = limits.processes {
+            group.write("pids.max", &n.to_string())?;
+        }
+        Ok(group)
+    }
+
+    fn write(&self, file: &str, value: &str) -> std::io::Result<()> {
+        std::fs::write(self.path.join(file), value)
+    }
+
+    /// Reads counter key from events file, 0 if it can't be read
+    fn event_count(&self, file: &str, key: &str) -> u64 {
+        std::fs::read_to_string(self.path.join(file))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .find(|(k, _)| *k == key)
+            .and_then(|(_, n)| n.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Kills all processes left in the group and removes it
+    pub async fn remove(self) {
+        if let Err(e) = self.write("cgroup.kill", "1") {
+            debug!("could not kill cgroup {}: {}", self.path.display(), e);
+        }
+        drop(self.procs);
+        for _ in 0..CGROUP_REMOVE_ATTEMPTS {
+            match std::fs::remove_dir(&self.path) {
+                Ok(_) => return,
+                // Processes might still be exiting
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => tokio::time::sleep(Duration::from_millis(10)).await,
+                Err(e) => {
+                    warn!("could not remove cgroup {}: {}", self.path.display(), e);
+                    return;
+                },
+            }
+        }
+        warn!("could not remove cgroup {}, processes are left in it", self.path.display());
+    }
+}
@@ -0,0 +1,126 @@
+use std::{collections::BTreeMap, error::Error, path::{Component, Path}};
+
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+
+use crate::{hash::hash, zip::list_files};
+
+/// Content hashes of all files belonging to an uploaded project state
+///
+/// Used on both sides to determine which files need to be sent with an
+/// incremental update, and to verify the resulting project state
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    files: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Hash all files in dir that do not match any of the exclude patterns
+    pub async fn from_dir(dir: impl AsRef<Path>, exclude: &[Regex]) -> Result<Self, Box<dyn Error>> {
+        let mut manifest = Manifest::default();
+        for path in list_files(dir, exclude)? {
+            let content = tokio::fs::read(path.as_path()).await?;
+            manifest.insert(path.to_string_lossy().to_string(), hash(&content).await);
+        }
+        Ok(manifest)
+    }
+
+    pub fn insert(&mut self, path: String, hash: String) {
+        self.files.insert(path, hash);
+    }
+
+    /// Adds all entries of other, replacing existing hashes
+    pub fn extend(&mut self, other: Manifest) {
+        self.files.extend(other.files);
+    }
+
+    pub fn remove(&mut self, path: &str) -> bool {
+        self.files.remove(path).is_some()
+    }
+
+    /// Checks whether the relative path is part of the manifest, paths of
+    /// uploaded files may start with `./`
+    pub fn contains(&self, path: &str) -> bool {
+        self.files.contains_key(path) || self.files.contains_key(&format!("./{}", path))
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &String> {
+        self.files.keys()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Returns the paths which were changed or added and the paths which were
+    /// deleted in the newer manifest
+    pub fn diff(&self, newer: &Manifest) -> (Vec<String>, Vec<String>) {
+        let changed = newer.files.iter()
+            .filter(|(path, hash)| self.files.get(*path) != Some(*hash))
+            .map(|(path, _)| path.clone())
+            .collect();
+        let deleted = self.files.keys()
+            .filter(|path| !newer.files.contains_key(*path))
+            .cloned()
+            .collect();
+        (changed, deleted)
+    }
+
+    /// Calculates a single hash over all paths and their content hashes
+    pub async fn tree_hash(&self) -> String {
+        let mut buf = String::new();
+        for (path, hash) in self.files.iter() {
+            buf.push_str(path.as_str());
+            buf.push('\0');
+            buf.push_str(hash.as_str());
+            buf.push('\n');
+        }
+        hash(buf).await
+    }
+}
+
+/// Checks that path does not point outside of the directory it is relative to
+pub fn is_contained_path(path: &str) -> bool {
+    Path::new(path).components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(files: &[(&str, &str)]) -> Manifest {
+        let mut manifest = Manifest::default();
+        for (path, hash) in files {
+            manifest.insert(path.to_string(), hash.to_string());
+        }
+        manifest
+    }
+
+    #[test]
+    fn diff_lists_changed_added_and_deleted_paths() {
+        let older = manifest(&[("a.txt", "1"), ("b.txt", "2"), ("c.txt", "3")]);
+        let newer = manifest(&[("a.txt", "1"), ("b.txt", "4"), ("d.txt", "5")]);
+        let (changed, deleted) = older.diff(&newer);
+        assert_eq!(changed, vec![String::from("b.txt"), String::from("d.txt")]);
+        assert_eq!(deleted, vec![String::from("c.txt")]);
+        assert_eq!(newer.diff(&newer), (vec![], vec![]));
+    }
+
+    #[tokio::test]
+    async fn tree_hash_depends_on_content_only() {
+        let hash = manifest(&[("a.txt", "1"), ("b.txt", "2")]).tree_hash().await;
+        assert_eq!(manifest(&[("b.txt", "2"), ("a.txt", "1")]).tree_hash().await, hash);
+        assert_ne!(manifest(&[("a.txt", "1"), ("b.txt", "3")]).tree_hash().await, hash);
+        assert_ne!(manifest(&[("a.txt", "1"), ("c.txt", "2")]).tree_hash().await, hash);
+    }
+
+    #[test]
+    fn rejects_paths_escaping_the_directory() {
+        assert!(is_contained_path("a.txt"));
+        assert!(is_contained_path("./src/main.rs"));
+        assert!(!is_contained_path("../a.txt"));
+        assert!(!is_contained_path("src/../../a.txt"));
+        assert!(!is_contained_path("/etc/passwd"));
+    }
+}
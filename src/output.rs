@@ -0,0 +1,158 @@
+use std::{collections::VecDeque, path::{Path, PathBuf}};
+
+use crate::pb::{OutputStream, Phase};
+
+/// How output of test commands is captured
+#[derive(Debug, Clone, Default)]
+pub struct OutputSettings {
+    /// Bytes kept from the start and from the end of each output stream,
+    /// everything is kept if unset
+    pub limit: Option<usize>,
+    /// Directory the complete output is written to, not spooled if unset
+    pub spool_dir: Option<PathBuf>,
+    /// Directory artifacts are collected to, not collected if unset
+    pub artifact_dir: Option<PathBuf>,
+}
+
+impl OutputSettings {
+    /// Returns the file complete output of a command is spooled to
+    pub fn spool_file(&self, phase: Phase, index: usize, iteration: u32, attempt: u32, stream: OutputStream) -> Option<PathBuf> {
+        self.spool_dir.as_ref()
+            .map(|dir| spool_file(dir, phase, index, iteration, attempt, stream))
+    }
+}
+
+/// Location of spooled output of a command within the spool directory of a run
+/// Repetitions and retries of a test are told apart by iteration and attempt.
+pub fn spool_file(dir: &Path, phase: Phase, index: usize, iteration: u32, attempt: u32, stream: OutputStream) -> PathBuf {
+    let phase = match phase {
+        Phase::Test => "test",
+        Phase::Setup => "setup",
+        Phase::Teardown => "teardown",
+    };
+    let stream = match stream {
+        OutputStream::Stdout => "stdout",
+        OutputStream::Stderr => "stderr",
+    };
+    let mut name = format!("{}-{}", phase, index);
+    if iteration > 0 {
+        name = format!("{}@{}", name, iteration);
+    }
+    if attempt > 0 {
+        name = format!("{}-{}", name, attempt);
+    }
+    dir.join(format!("{}.{}", name, stream))
+}
+
+/// Output of a single stream, possibly truncated
+#[derive(Debug, Clone, Default)]
+pub struct CapturedOutput {
+    pub data: Vec<u8>,
+    /// Number of bytes written by the command
+    pub size: u64,
+    pub truncated: bool,
+}
+
+/// Collects output, keeping only head and tail of it if limited
+pub struct OutputCapture {
+    limit: Option<usize>,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    size: u64,
+}
+
+impl OutputCapture {
+    pub fn new(limit: Option<usize>) -> Self {
+        OutputCapture {
+            limit,
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    pub fn push(&mut self, mut data: &[u8]) {
+        self.size += data.len() as u64;
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => {
+                self.head.extend_from_slice(data);
+                return;
+            },
+        };
+        // Fill head first, everything after it passes through the tail
+        let n = limit.saturating_sub(self.head.len()).min(data.len());
+        self.head.extend_from_slice(&data[..n]);
+        data = &data[n..];
+        if data.len() > limit {
+            data = &data[data.len() - limit..];
+        }
+        self.tail.extend(data);
+        if self.tail.len() > limit {
+            let excess = self.tail.len() - limit;
+            self.tail.drain(..excess);
+        }
+    }
+
+    /// Returns kept output, with a marker in place of the dropped part
+    pub fn finish(self) -> CapturedOutput {
+        let kept = (self.head.len() + self.tail.len()) as u64;
+        let truncated = kept < self.size;
+        let mut data = self.head;
+        if truncated {
+            data.extend_from_slice(format!("\n[... {} bytes truncated ...]\n", self.size - kept).as_bytes());
+        }
+        data.extend(self.tail);
+        CapturedOutput { data, size: self.size, truncated }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(limit: Option<usize>, chunks: &[&[u8]]) -> CapturedOutput {
+        let mut capture = OutputCapture::new(limit);
+        for chunk in chunks {
+            capture.push(chunk);
+        }
+        capture.finish()
+    }
+
+    #[test]
+    fn keeps_everything_without_limit() {
+        let output = capture(None, &[b"abc", b"def"]);
+        assert_eq!(output.data, b"abcdef");
+        assert_eq!(output.size, 6);
+        assert!(!output.truncated);
+    }
+
+    #[test]
+    fn keeps_output_up_to_twice_the_limit() {
+        let output = capture(Some(3), &[b"ab", b"cd", b"ef"]);
+        assert_eq!(output.data, b"abcdef");
+        assert!(!output.truncated);
+    }
+
+    #[test]
+    fn splits_head_and_tail_across_chunks() {
+        let output = capture(Some(3), &[b"ab", b"cdefg", b"h", b"ij"]);
+        assert_eq!(output.data, b"abc\n[... 4 bytes truncated ...]\nhij");
+        assert_eq!(output.size, 10);
+        assert!(output.truncated);
+    }
+
+    #[test]
+    fn keeps_tail_of_large_chunk() {
+        let output = capture(Some(2), &[b"abcdefghij"]);
+        assert_eq!(output.data, b"ab\n[... 6 bytes truncated ...]\nij");
+        assert_eq!(output.size, 10);
+    }
+
+    #[test]
+    fn names_spool_files_by_iteration_and_attempt() {
+        let dir = Path::new("/spool");
+        assert_eq!(spool_file(dir, Phase::Test, 2, 0, 0, OutputStream::Stdout), dir.join("test-2.stdout"));
+        assert_eq!(spool_file(dir, Phase::Setup, 0, 3, 1, OutputStream::Stderr), dir.join("setup-0@3-1.stderr"));
+    }
+}
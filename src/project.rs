@@ -1,35 +1,458 @@
-use std::{error::Error, path::PathBuf, process::Stdio};
+use std::{collections::BTreeMap, error::Error, os::unix::process::ExitStatusExt, path::{Path, PathBuf}, process::{ExitStatus, Stdio}, sync::Arc, time::{Duration, Instant}};
 
-use log::{debug, info};
+use chrono::{DateTime, Utc};
+use futures_util::future::{FusedFuture, FutureExt, join_all};
+use log::{debug, error, info, warn};
+use rand::{SeedableRng, seq::SliceRandom};
+use rand_chacha::ChaCha8Rng;
+use regex::Regex;
 use serde::{Serialize, Deserialize};
-use tokio::process::Command;
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWriteExt}, process::Command, sync::{Semaphore, mpsc::{Sender, error::TrySendError}, watch}};
 
+use crate::artifacts;
+use crate::capsule::{Capsule, CapsuleChoice, CapsuleConfig, SandboxCapsule, TransparentCapsule};
+use crate::limits::ResourceLimits;
+use crate::manifest::{Manifest, is_contained_path};
+use crate::output::{CapturedOutput, OutputCapture, OutputSettings};
 use crate::zip::ZipFile;
-use crate::pb::TestResult;
+use crate::pb::{OutputChunk, OutputStream, Phase, ProjectInfo, ResourceLimit, RunRequest, RunState, RunSummary, TestDefinition, TestEvent, TestFilter, TestFinished, TestResult, TestResults, TestStarted, TestStatus, test_event::Event};
 
-pub type TestOutput = (String, Option<i32>, Vec<u8>, Vec<u8>);
+/// Time output of a killed test is still read for, in case processes that
+/// escaped its process group keep the pipes open
+const OUTPUT_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Everything we know about a finished test process
+#[derive(Debug, Clone)]
+pub struct TestOutput {
+    pub command: String,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Number of bytes written to stdout, even if not all of them were kept
+    pub stdout_size: u64,
+    pub stderr_size: u64,
+    /// Output was cut to the configured limit
+    pub truncated: bool,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration: Duration,
+    pub status: TestStatus,
+    /// Limit the test ran into if status is LimitExceeded
+    pub exceeded_limit: ResourceLimit,
+    /// Failed earlier attempts of a retried test
+    pub attempts: Vec<TestOutput>,
+    /// Iteration the test was run in if tests are repeated
+    pub iteration: u32,
+    /// Position of the test in the project's test list
+    pub index: usize,
+    pub name: Option<String>,
+}
+
+impl TestOutput {
+    /// Collects output of a process started at started_at/start
+    pub fn new(command: String, status: ExitStatus, stdout: CapturedOutput, stderr: CapturedOutput, started_at: DateTime<Utc>, start: Instant) -> Self {
+        // Report success if we have an exit code 0
+        let test_status = match status.code() {
+            Some(0) => TestStatus::Passed,
+            _ => TestStatus::Failed,
+        };
+        TestOutput {
+            command,
+            exit_code: status.code(),
+            signal: status.signal(),
+            truncated: stdout.truncated || stderr.truncated,
+            stdout_size: stdout.size,
+            stderr_size: stderr.size,
+            stdout: stdout.data,
+            stderr: stderr.data,
+            started_at,
+            finished_at: Utc::now(),
+            duration: start.elapsed(),
+            status: test_status,
+            exceeded_limit: ResourceLimit::NoLimit,
+            attempts: Vec::new(),
+            iteration: 0,
+            index: 0,
+            name: None,
+        }
+    }
+
+    /// Test passed, possibly only on retry
+    pub fn passed(&self) -> bool {
+        matches!(self.status, TestStatus::Passed | TestStatus::Flaky)
+    }
+
+    /// Attributes output to test case i
+    fn of_case(mut self, i: usize, case: &TestCase) -> Self {
+        self.index = i;
+        self.name = case.name.clone();
+        self
+    }
+
+    /// Output for a test which could not be executed because of error
+    pub fn error(command: String, error: &str) -> Self {
+        let mut res = TestOutput::skipped(command, error);
+        res.status = TestStatus::Error;
+        res
+    }
+
+    /// Output for a test which was not executed
+    pub fn skipped(command: String, reason: &str) -> Self {
+        let now = Utc::now();
+        TestOutput {
+            command,
+            exit_code: None,
+            signal: None,
+            stdout: Vec::new(),
+            stderr: reason.as_bytes().to_vec(),
+            stdout_size: 0,
+            stderr_size: reason.len() as u64,
+            truncated: false,
+            started_at: now,
+            finished_at: now,
+            duration: Duration::ZERO,
+            status: TestStatus::Skipped,
+            exceeded_limit: ResourceLimit::NoLimit,
+            attempts: Vec::new(),
+            iteration: 0,
+            index: 0,
+            name: None,
+        }
+    }
+}
+
+/// Outputs of all commands executed for a run
+#[derive(Debug, Clone, Default)]
+pub struct RunOutput {
+    pub setup: Vec<TestOutput>,
+    pub tests: Vec<TestOutput>,
+    pub teardown: Vec<TestOutput>,
+    /// Number of iterations run if tests were repeated
+    pub iterations: Option<u32>,
+    /// Relative paths of collected artifacts
+    pub artifacts: Vec<String>,
+}
+
+/// How often and in which order selected tests are run within a single run
+#[derive(Debug, Clone, Copy)]
+pub struct Schedule {
+    pub times: u32,
+    /// Stop after the first iteration with a failed test
+    pub until_failure: bool,
+    /// Tests are shuffled with this seed if set
+    pub seed: Option<u64>,
+}
+
+impl From<&RunRequest> for Schedule {
+    fn from(request: &RunRequest) -> Self {
+        Schedule {
+            times: request.repeat.unwrap_or(1).max(1),
+            until_failure: request.until_failure,
+            seed: request.seed,
+        }
+    }
+}
+
+/// Returns selected in random order determined by rng
+fn shuffled(selected: &[usize], rng: &mut ChaCha8Rng) -> Vec<usize> {
+    let mut order = selected.to_vec();
+    order.shuffle(rng);
+    order
+}
+
+/// Number of events buffered for each receiver
+pub const EVENT_BUFFER: usize = 256;
+
+/// Receives progress events while tests are executed
+pub type EventSender = Sender<TestEvent>;
+
+/// Time a receiver may fall behind before it is given up on
+const EVENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Passes event on to tx, returning false once the receiver is gone
+/// Output is dropped while the receiver falls behind, it is still part of
+/// the results. Other events are waited for, so none of them get lost, but
+/// only for EVENT_TIMEOUT, a stalled receiver must not hold up the run.
+/// Returns false then as well, the receiver should be dropped.
+pub async fn forward_event(tx: &EventSender, event: TestEvent) -> bool {
+    if let Some(Event::Output(_)) = &event.event {
+        return !matches!(tx.try_send(event), Err(TrySendError::Closed(_)));
+    }
+    tx.send_timeout(event, EVENT_TIMEOUT).await.is_ok()
+}
+
+async fn send_event(events: Option<&EventSender>, event: Event) {
+    if let Some(tx) = events {
+        // Receiver might be gone already, tests still run to completion
+        forward_event(tx, TestEvent { event: Some(event) }).await;
+    }
+}
+
+/// Ways to observe and control a test run from the outside
+#[derive(Clone, Default)]
+pub struct RunControl {
+    /// Receives progress events
+    pub events: Option<EventSender>,
+    /// Run is cancelled as soon as this turns true
+    pub cancel: Option<watch::Receiver<bool>>,
+    /// Limits the number of tests running at the same time, shared by runs
+    pub slots: Option<Arc<Semaphore>>,
+    /// Phase events are reported for
+    pub phase: Phase,
+    /// Number of earlier attempts to run the current test
+    pub attempt: u32,
+    /// Iteration the current test is run in if tests are repeated
+    pub iteration: u32,
+    /// How much output is kept and where all of it is spooled to
+    pub output: OutputSettings,
+}
+
+impl RunControl {
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref()
+            .map(|c| *c.borrow())
+            .unwrap_or(false)
+    }
+
+    /// Resolves once the run is cancelled, never if it can not be cancelled
+    async fn cancelled(&self) {
+        match self.cancel.clone() {
+            Some(mut cancel) => {
+                while !*cancel.borrow() {
+                    if cancel.changed().await.is_err() {
+                        // Sender is gone, run can not be cancelled anymore
+                        std::future::pending::<()>().await;
+                    }
+                }
+            },
+            None => std::future::pending().await,
+        }
+    }
+}
 
 impl From<TestOutput> for TestResult {
     fn from(t: TestOutput) -> Self {
-        let (cmd, code, stdout, stderr) = t;
-        // Report success if we have an exit code 0
-        let success = code
-            .filter(|x| *x == 0)
-            .is_some();
+        let success = t.passed();
         TestResult {
-            command: cmd,
-            stdout,
-            stderr,
+            command: t.command,
+            stdout: t.stdout,
+            stderr: t.stderr,
             success,
+            exit_code: t.exit_code,
+            signal: t.signal,
+            started_at: t.started_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            finished_at: t.finished_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            duration_ms: t.duration.as_millis() as u64,
+            status: t.status as i32,
+            index: t.index as u32,
+            name: t.name,
+            stdout_size: t.stdout_size,
+            stderr_size: t.stderr_size,
+            truncated: t.truncated,
+            exceeded_limit: t.exceeded_limit as i32,
+            attempts: t.attempts.into_iter().map(TestResult::from).collect(),
+            iteration: t.iteration,
+        }
+    }
+}
+
+/// Single test command of a project
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "TestCaseRepr")]
+pub struct TestCase {
+    pub command: Vec<String>,
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    /// Test may run at the same time as other parallel tests
+    pub parallel: bool,
+    /// Variables set for this test, on top of the project's
+    pub env: BTreeMap<String, String>,
+    /// Times the test is run again after failing
+    pub retries: u32,
+}
+
+/// Tests used to be stored as plain commands
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TestCaseRepr {
+    Command(Vec<String>),
+    Full {
+        command: Vec<String>,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default)]
+        parallel: bool,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        #[serde(default)]
+        retries: u32,
+    },
+}
+
+impl From<TestCaseRepr> for TestCase {
+    fn from(repr: TestCaseRepr) -> Self {
+        match repr {
+            TestCaseRepr::Command(command) => TestCase { command, ..Default::default() },
+            TestCaseRepr::Full { command, name, tags, parallel, env, retries } => TestCase { command, name, tags, parallel, env, retries },
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+impl From<TestDefinition> for TestCase {
+    fn from(def: TestDefinition) -> Self {
+        // FIXME: add proper error handling?
+        TestCase {
+            command: shell_words::split(&def.command).unwrap_or_default(),
+            name: def.name,
+            tags: def.tags,
+            parallel: def.parallel,
+            env: def.env.into_iter().collect(),
+            retries: def.retries,
+        }
+    }
+}
+
+impl From<TestCase> for TestDefinition {
+    fn from(case: TestCase) -> Self {
+        TestDefinition {
+            command: shell_words::join(case.command),
+            name: case.name,
+            tags: case.tags,
+            parallel: case.parallel,
+            env: case.env.into_iter().collect(),
+            retries: case.retries,
+        }
+    }
+}
+
+impl TestCase {
+    /// Checks whether this test is selected by filter
+    fn matches(&self, i: usize, filter: &TestFilter, pattern: Option<&Regex>) -> bool {
+        filter.indices.contains(&(i as u32))
+            || self.name.as_ref().map(|n| filter.names.contains(n)).unwrap_or(false)
+            || self.tags.iter().any(|t| filter.tags.contains(t))
+            || pattern.map(|p| p.is_match(&shell_words::join(&self.command))).unwrap_or(false)
+    }
+}
+
+/// Environment a test process is started with
+#[derive(Debug, Clone, Default)]
+pub struct TestEnv {
+    /// Start from an empty environment instead of the server's
+    pub clear: bool,
+    /// Variables set on top of the inherited environment
+    pub vars: BTreeMap<String, String>,
+}
+
+impl TestEnv {
+    pub fn apply_to(&self, cmd: &mut Command) {
+        if self.clear {
+            cmd.env_clear();
+        }
+        cmd.envs(&self.vars);
+    }
+
+    /// Shell commands setting up this environment, for capsules which can
+    /// not set it directly
+    /// They are passed on stdin rather than as arguments, command lines are
+    /// visible to everyone on the host.
+    pub fn as_shell_script(&self) -> String {
+        let mut script = String::new();
+        if self.clear {
+            script.push_str(CLEAR_ENV_SCRIPT);
+            script.push('\n');
+        }
+        for (k, v) in self.vars.iter() {
+            script.push_str(&format!("export {}\n", shell_words::quote(&format!("{}={}", k, v))));
+        }
+        script
+    }
+}
+
+/// Shell script unsetting all exported variables, like env -i
+/// PATH is kept as a shell variable, commands can still be found without
+/// passing it on to them.
+const CLEAR_ENV_SCRIPT: &str = r#"path=$PATH
+for name in $(env | sed -n 's/^\([A-Za-z_][A-Za-z0-9_]*\)=.*/\1/p'); do unset "$name"; done
+PATH=$path"#;
+
+/// Outcome of the last test run of a project
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LastRun {
+    run_id: String,
+    timestamp: String,
+    hash: String,
+    state: i32,
+    passed: u32,
+    total: u32,
+}
+
+impl From<LastRun> for RunSummary {
+    fn from(r: LastRun) -> Self {
+        RunSummary {
+            run_id: r.run_id,
+            timestamp: r.timestamp,
+            hash: r.hash,
+            state: r.state,
+            passed: r.passed,
+            total: r.total,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TestProject {
     name: String,
-    tests: Vec<Vec<String>>,
+    tests: Vec<TestCase>,
     hash: Option<String>,
+    /// Seconds after which a single test is killed
+    #[serde(default)]
+    test_timeout: Option<u64>,
+    /// Seconds after which the whole run is stopped
+    #[serde(default)]
+    run_timeout: Option<u64>,
+    /// Number of parallel tests run at the same time, unlimited if unset
+    #[serde(default)]
+    max_parallel: Option<u32>,
+    /// Variables set for all tests
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// Tests don't inherit the server's environment
+    #[serde(default)]
+    clear_env: bool,
+    /// Server variables passed to tests even if the environment is cleared
+    #[serde(default)]
+    pass_env: Vec<String>,
+    /// Commands run before the tests, tests are skipped if one fails
+    #[serde(default)]
+    setup: Vec<Vec<String>>,
+    /// Commands run after the tests, no matter how they ended
+    #[serde(default)]
+    teardown: Vec<Vec<String>>,
+    /// Hashes of all files sent by the client, used for incremental updates
+    #[serde(default)]
+    manifest: Manifest,
+    /// Time of the last applied update
+    #[serde(default)]
+    last_update: Option<String>,
+    #[serde(default)]
+    last_run: Option<LastRun>,
+    /// Overrides the server's default capsule if set
+    #[serde(default)]
+    capsule: Option<CapsuleChoice>,
+    /// KB of output kept from start and end of each stream, overrides the
+    /// server's default if set
+    #[serde(default)]
+    output_limit_kb: Option<u64>,
+    /// Resources each test command may use
+    #[serde(default)]
+    limits: ResourceLimits,
+    /// Glob patterns of files collected after a run
+    #[serde(default)]
+    artifacts: Vec<String>,
 }
 
 impl TestProject {
@@ -44,16 +467,55 @@ impl TestProject {
         (name, hash)
     }
 
-    pub fn get_dir(&self, base_dir: &PathBuf) -> PathBuf {
-        let mut dir = base_dir.clone();
-        dir.push(self.name.as_str());
-        return dir;
+    pub fn get_dir(&self, base_dir: &Path) -> PathBuf {
+        base_dir.join(self.name.as_str())
+    }
+
+    /// Replaces test configuration with the one of other, keeping uploaded
+    /// code and run state
+    pub fn update_config(&mut self, mut other: TestProject) {
+        other.hash = self.hash.take();
+        other.manifest = std::mem::take(&mut self.manifest);
+        other.last_update = self.last_update.take();
+        other.last_run = self.last_run.take();
+        *self = other;
+    }
+
+    fn touch(&mut self) {
+        self.last_update = Some(Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+    }
+
+    /// Remembers outcome of a test run
+    pub fn record_run(&mut self, run_id: String, state: RunState, results: Option<&TestResults>) {
+        let (timestamp, hash) = match results {
+            Some(r) => (r.timestamp.clone(), r.hash.clone()),
+            None => (Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true), self.hash.clone().unwrap_or_default()),
+        };
+        let tests = results.map(|r| r.results.as_slice()).unwrap_or_default();
+        self.last_run = Some(LastRun {
+            run_id,
+            timestamp,
+            hash,
+            state: state as i32,
+            passed: tests.iter().filter(|t| t.success).count() as u32,
+            total: tests.len() as u32,
+        });
+    }
+
+    /// Returns project definition and current state
+    pub fn info(&self) -> ProjectInfo {
+        ProjectInfo {
+            project: Some(crate::pb::Project::from(self.clone())),
+            hash: self.hash.clone(),
+            last_update: self.last_update.clone(),
+            last_run: self.last_run.clone().map(RunSummary::from),
+        }
     }
 
     /// Use supplied data to apply update
     /// checks whether update can be applied before and returns Ok(false) if no
     /// update can be applied
-    pub async fn apply_update(&mut self, content: ZipFile, hash: String, base_dir: &PathBuf) -> Result<(), String> {
+    pub async fn apply_update(&mut self, content: ZipFile, hash: String, base_dir: &Path) -> Result<(), String> {
         if self.hash.is_some() {
             // Project is not empty, cannot apply update
             return Err(format!("Project '{}' can not apply update, as it is in an unsuitable state", self.name.as_str()));
@@ -62,77 +524,837 @@ impl TestProject {
         if !content.compare_hash(&hash) {
             return Err(format!("Hashsum mismatch '{}'!='{}'", content.get_hash(), hash.as_str()));
         }
+        let manifest = content.manifest()
+            .await
+            .map_err(|e| format!("Could not read zip archive: {}", e))?;
         // Try to extract content and apply update
         let dir = self.get_dir(base_dir);
-        let _ = content.extract_into(&dir)
+        content.extract_into(&dir)
             .await
             .map_err(|e| format!("Could not extract zip archive: {}", e))?;
         // Update hash
         self.hash = Some(hash);
+        self.manifest = manifest;
+        self.touch();
         // Applied update successfully
         Ok(())
     }
 
-    pub async fn execute_all_tests(&self, base_dir: &PathBuf) -> Result<Vec<TestOutput>, Box<dyn Error>> {
+    /// Apply diff update on top of the current project state
+    /// content only contains changed and added files, deleted files are
+    /// removed from the project directory. The resulting state is verified
+    /// against newhash before anything is written. content is removed
+    /// afterwards, whether it could be applied or not.
+    pub async fn apply_increment(&mut self, content: ZipFile, prevhash: String, newhash: String, deleted: Vec<String>, base_dir: &Path) -> Result<(), String> {
+        let res = self.extract_increment(&content, prevhash, newhash, deleted, base_dir).await;
+        if let Err(e) = content.remove().await {
+            warn!("could not remove increment of {}: {}", self.name.as_str(), e);
+        }
+        res
+    }
+
+    async fn extract_increment(&mut self, content: &ZipFile, prevhash: String, newhash: String, deleted: Vec<String>, base_dir: &Path) -> Result<(), String> {
+        match &self.hash {
+            Some(hash) if *hash == prevhash => (),
+            Some(hash) => return Err(format!("Project '{}' is at '{}', increment expects '{}'", self.name.as_str(), hash.as_str(), prevhash.as_str())),
+            None => return Err(format!("Project '{}' is empty, send a complete update first", self.name.as_str())),
+        }
+        if self.manifest.is_empty() {
+            return Err(format!("Project '{}' has no file manifest, re-register it to enable incremental updates", self.name.as_str()));
+        }
+        if let Some(path) = deleted.iter().find(|p| !is_contained_path(p.as_str())) {
+            return Err(format!("Invalid path '{}' in deleted files", path.as_str()));
+        }
+        // Compute resulting manifest and check that it matches the new hash
+        let changed = content.manifest()
+            .await
+            .map_err(|e| format!("Could not read zip archive: {}", e))?;
+        if let Some(path) = changed.paths().find(|p| !is_contained_path(p.as_str())) {
+            return Err(format!("Invalid path '{}' in increment", path.as_str()));
+        }
+        let mut manifest = self.manifest.clone();
+        manifest.extend(changed);
+        for path in deleted.iter() {
+            manifest.remove(path.as_str());
+        }
+        let tree_hash = manifest.tree_hash().await;
+        if tree_hash != newhash {
+            return Err(format!("Hashsum mismatch '{}'!='{}'", tree_hash.as_str(), newhash.as_str()));
+        }
+        // Write changed files and remove deleted ones
+        let dir = self.get_dir(base_dir);
+        content.extract_into(&dir)
+            .await
+            .map_err(|e| format!("Could not extract zip archive: {}", e))?;
+        for path in deleted.iter() {
+            match tokio::fs::remove_file(dir.join(path.as_str())).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(format!("Could not remove '{}': {}", path.as_str(), e));
+                },
+                _ => (),
+            }
+        }
+        // Update hash
+        self.hash = Some(newhash);
+        self.manifest = manifest;
+        self.touch();
+        Ok(())
+    }
+
+    /// Returns positions of all tests selected by filter
+    /// All tests are selected without filter or with an empty one
+    pub fn select_tests(&self, filter: Option<&TestFilter>) -> Result<Vec<usize>, String> {
+        let filter = match filter {
+            Some(f) if !(f.indices.is_empty() && f.names.is_empty() && f.tags.is_empty() && f.pattern.is_none()) => f,
+            _ => return Ok((0..self.tests.len()).collect()),
+        };
+        if let Some(i) = filter.indices.iter().find(|i| **i as usize >= self.tests.len()) {
+            return Err(format!("Project '{}' has no test at position {}", self.name.as_str(), i));
+        }
+        let pattern = filter.pattern.as_ref()
+            .map(|p| Regex::new(p.as_str()))
+            .transpose()
+            .map_err(|e| format!("Invalid test pattern: {}", e))?;
+        Ok(self.tests.iter()
+            .enumerate()
+            .filter(|(i, case)| case.matches(*i, filter, pattern.as_ref()))
+            .map(|(i, _)| i)
+            .collect())
+    }
+
+    /// Returns environment for test case
+    /// Allowed server variables are overridden by project variables, which
+    /// are overridden by variables of the test itself
+    pub fn test_env(&self, case: &TestCase) -> TestEnv {
+        let mut vars = BTreeMap::new();
+        if self.clear_env {
+            for name in self.pass_env.iter() {
+                if let Ok(value) = std::env::var(name) {
+                    vars.insert(name.clone(), value);
+                }
+            }
+        }
+        vars.extend(self.env.clone());
+        vars.extend(case.env.clone());
+        TestEnv { clear: self.clear_env, vars }
+    }
+
+    /// Returns the capsule tests of this project are run in
+    pub fn capsule<'a>(&'a self, capsules: &'a CapsuleConfig) -> &'a CapsuleChoice {
+        self.capsule.as_ref().unwrap_or(&capsules.default)
+    }
+
+    /// Returns bytes of output kept from start and end of each stream, the
+    /// server's default_kb applies unless the project sets its own limit
+    /// A limit of 0 keeps all output.
+    pub fn output_limit(&self, default_kb: u64) -> Option<usize> {
+        match self.output_limit_kb.unwrap_or(default_kb) {
+            0 => None,
+            kb => Some(kb as usize * 1024),
+        }
+    }
+
+    /// Runs all tests selected by the request one after another, inside of
+    /// the project's capsule or the server's default one
+    /// Progress is reported to and cancellation is taken from control
+    pub async fn execute_all_tests(&self, base_dir: &Path, request: &RunRequest, capsules: &CapsuleConfig, control: RunControl) -> Result<RunOutput, Box<dyn Error>> {
         if self.hash.is_none() {
             // Project is still empty, cannot run tests
             info!("cannot run requested tests for {}, project is empty", self.name.as_str());
             return Err(Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "project is not initialized")));
         }
-        let mut results = Vec::with_capacity(self.tests.len());
-        for (i, test) in self.tests.iter().enumerate() {
-            let dir = self.get_dir(base_dir);
-            info!("{}: Running test {}/{}", self.name.as_str(), i+1, self.tests.len());
-            let res = run_test(test, &dir).await?;
+        let selected = self.select_tests(request.filter.as_ref())?;
+        let schedule = Schedule::from(request);
+        match self.capsule(capsules) {
+            CapsuleChoice::Transparent => {
+                self.execute_in(TransparentCapsule::new(capsules.cgroup.clone()), self.limits, base_dir, &selected, schedule, &control).await
+            },
+            CapsuleChoice::Kubernetes(options) => {
+                let capsule = capsules.kubernetes.clone()
+                    .ok_or("kubernetes capsule is not configured on this server")?;
+                self.execute_in(capsule, options.clone(), base_dir, &selected, schedule, &control).await
+            },
+            CapsuleChoice::Container(options) => {
+                let capsule = capsules.container.clone()
+                    .ok_or("container capsule is not configured on this server")?;
+                self.execute_in(capsule, options.clone(), base_dir, &selected, schedule, &control).await
+            },
+            CapsuleChoice::Sandbox(options) => {
+                self.execute_in(SandboxCapsule::new(capsules.cgroup.clone(), self.limits), options.clone(), base_dir, &selected, schedule, &control).await
+            },
+        }
+    }
+
+    /// Runs selected tests inside of capsule, which is discarded afterwards
+    async fn execute_in<C>(&self, mut capsule: C, args: C::Args, base_dir: &Path, selected: &[usize], schedule: Schedule, control: &RunControl) -> Result<RunOutput, Box<dyn Error>>
+        where C: Capsule<Error = Box<dyn Error>> + Send + Sync, C::Args: Send
+    {
+        let ident = format!("{}-{}", self.name.as_str(), Utc::now().timestamp_millis());
+        let dir = self.get_dir(base_dir);
+        if control.output.artifact_dir.is_some() {
+            self.clear_artifacts(&dir);
+        }
+        capsule.encapsulate(ident, &dir, args).await?;
+        let mut res = self.execute_phases(&capsule, selected, schedule, control).await;
+        if let Some(dest) = &control.output.artifact_dir {
+            res.artifacts = self.collect_artifacts(&capsule, dest)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("could not collect artifacts of {}: {}", self.name.as_str(), e);
+                    Vec::new()
+                });
+        }
+        if let Err(e) = capsule.discard().await {
+            error!("could not discard capsule of {}: {}", self.name.as_str(), e);
+        }
+        Ok(res)
+    }
+
+    /// Removes files matching the project's artifact patterns from dir,
+    /// which were not uploaded by the client
+    /// Capsules start from the project directory, artifacts left behind in
+    /// it by earlier runs would be collected again otherwise.
+    fn clear_artifacts(&self, dir: &Path) {
+        if self.artifacts.is_empty() {
+            return;
+        }
+        if self.manifest.is_empty() {
+            warn!("not clearing artifacts of {}, project has no file manifest", self.name.as_str());
+            return;
+        }
+        let res = artifacts::parse_globs(&self.artifacts)
+            .and_then(|patterns| artifacts::clear(dir, &patterns, |p| self.manifest.contains(p)));
+        if let Err(e) = res {
+            warn!("could not clear artifacts of {}: {}", self.name.as_str(), e);
+        }
+    }
+
+    /// Copies files matching the project's artifact patterns out of capsule
+    /// into dest, returning their relative paths
+    async fn collect_artifacts<C>(&self, capsule: &C, dest: &Path) -> Result<Vec<String>, String>
+        where C: Capsule<Error = Box<dyn Error>> + Send + Sync
+    {
+        if self.artifacts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let patterns = artifacts::parse_globs(&self.artifacts)
+            .map_err(|e| e.to_string())?;
+        // Workspace is copied next to the artifacts if it can't be read in place
+        let scratch = dest.with_extension("workspace");
+        let workspace = capsule.workspace(&scratch)
+            .await
+            .map_err(|e| e.to_string())?;
+        let res = artifacts::collect(&workspace, &patterns, dest)
+            .map_err(|e| e.to_string());
+        if scratch.exists() {
+            if let Err(e) = tokio::fs::remove_dir_all(&scratch).await {
+                warn!("could not remove {}: {}", scratch.display(), e);
+            }
+        }
+        res
+    }
+
+    /// Runs setup, selected tests and teardown
+    /// Tests are skipped if setup fails, teardown is always run.
+    async fn execute_phases<C>(&self, capsule: &C, selected: &[usize], schedule: Schedule, control: &RunControl) -> RunOutput
+        where C: Capsule<Error = Box<dyn Error>> + Send + Sync
+    {
+        let deadline = self.run_timeout.map(|t| Instant::now() + Duration::from_secs(t));
+        let setup_control = RunControl { phase: Phase::Setup, ..control.clone() };
+        let setup = self.execute_commands(capsule, &self.setup, deadline, &setup_control).await;
+        let mut iterations = None;
+        // Same seed, same order
+        let mut rng = schedule.seed.map(ChaCha8Rng::seed_from_u64);
+        let tests = if setup.iter().all(|r| r.status == TestStatus::Passed) {
+            if schedule.times > 1 {
+                let (tests, n) = self.execute_repeated(capsule, selected, deadline, schedule, rng.as_mut(), control).await;
+                iterations = Some(n);
+                tests
+            } else if let Some(rng) = rng.as_mut() {
+                self.execute_selected(capsule, &shuffled(selected, rng), deadline, control).await
+            } else {
+                self.execute_selected(capsule, selected, deadline, control).await
+            }
+        } else {
+            // Setup might not have failed, but been cut short by the run
+            let (status, reason) = if control.is_cancelled() {
+                (TestStatus::Cancelled, "run was cancelled during setup")
+            } else if self.timeout(deadline) == Some(Duration::ZERO) {
+                (TestStatus::TimedOut, "run timeout exceeded during setup")
+            } else {
+                (TestStatus::SetupFailed, "setup failed")
+            };
+            info!("{}: Skipping {} tests, {}", self.name.as_str(), selected.len(), reason);
+            let mut tests = Vec::new();
+            for i in selected {
+                let case = &self.tests[*i];
+                let mut res = TestOutput::skipped(shell_words::join(&case.command), reason)
+                    .of_case(*i, case);
+                res.status = status;
+                send_event(control.events.as_ref(), Event::Finished(TestFinished {
+                    index: *i as u32,
+                    result: Some(TestResult::from(res.clone())),
+                    phase: Phase::Test as i32,
+                })).await;
+                tests.push(res);
+            }
+            tests
+        };
+        // Teardown can neither be cancelled nor run out of time
+        let teardown_control = RunControl { phase: Phase::Teardown, cancel: None, ..control.clone() };
+        let teardown = self.execute_commands(capsule, &self.teardown, None, &teardown_control).await;
+        RunOutput { setup, tests, teardown, iterations, artifacts: Vec::new() }
+    }
+
+    /// Runs selected tests again and again, returning results of all
+    /// iterations along with the number of iterations run
+    /// Output is only kept for failed results. Each iteration is shuffled
+    /// anew with rng if supplied.
+    async fn execute_repeated<C>(&self, capsule: &C, selected: &[usize], deadline: Option<Instant>, schedule: Schedule, mut rng: Option<&mut ChaCha8Rng>, control: &RunControl) -> (Vec<TestOutput>, u32)
+        where C: Capsule<Error = Box<dyn Error>> + Send + Sync
+    {
+        let mut tests = Vec::new();
+        let mut iterations = 0;
+        while iterations < schedule.times {
+            if iterations > 0 && (control.is_cancelled() || self.timeout(deadline) == Some(Duration::ZERO)) {
+                break;
+            }
+            info!("{}: Running iteration {}/{}", self.name.as_str(), iterations+1, schedule.times);
+            let iteration_control = RunControl { iteration: iterations, ..control.clone() };
+            let order = match rng.as_deref_mut() {
+                Some(rng) => shuffled(selected, rng),
+                None => selected.to_vec(),
+            };
+            let results = self.execute_selected(capsule, &order, deadline, &iteration_control).await;
+            iterations += 1;
+            let failed = results.iter().any(|r| !r.passed());
+            for mut res in results {
+                if res.passed() {
+                    res.stdout.clear();
+                    res.stderr.clear();
+                    forget_spooled(&iteration_control, &res).await;
+                }
+                tests.push(res);
+            }
+            if failed && schedule.until_failure {
+                info!("{}: Stopping after failed iteration {}", self.name.as_str(), iterations);
+                break;
+            }
+        }
+        (tests, iterations)
+    }
+
+    /// Limits test timeout to whatever is left until deadline
+    fn timeout(&self, deadline: Option<Instant>) -> Option<Duration> {
+        let test_timeout = self.test_timeout.map(Duration::from_secs);
+        let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        match (test_timeout, remaining) {
+            (Some(t), Some(r)) => Some(t.min(r)),
+            (t, r) => t.or(r),
+        }
+    }
+
+    /// Runs setup or teardown commands one after another, stopping after the
+    /// first failed one during setup
+    async fn execute_commands<C>(&self, capsule: &C, commands: &[Vec<String>], deadline: Option<Instant>, control: &RunControl) -> Vec<TestOutput>
+        where C: Capsule<Error = Box<dyn Error>> + Send + Sync
+    {
+        let env = self.test_env(&TestCase::default());
+        let mut results = Vec::with_capacity(commands.len());
+        for (i, command) in commands.iter().enumerate() {
+            let timeout = self.timeout(deadline);
+            let skip_reason = if control.is_cancelled() {
+                Some("run was cancelled before command was started")
+            } else if timeout == Some(Duration::ZERO) {
+                Some("run timeout exceeded before command was started")
+            } else {
+                None
+            };
+            let mut res = match skip_reason {
+                Some(reason) => TestOutput::skipped(shell_words::join(command), reason),
+                None => {
+                    info!("{}: Running {:?} command {}/{}", self.name.as_str(), control.phase, i+1, commands.len());
+                    send_event(control.events.as_ref(), Event::Started(TestStarted {
+                        index: i as u32,
+                        command: shell_words::join(command),
+                        phase: control.phase as i32,
+                        attempt: 0,
+                        iteration: 0,
+                    })).await;
+                    run_in(capsule, i, command, &env, timeout, control).await
+                },
+            };
+            res.index = i;
+            send_event(control.events.as_ref(), Event::Finished(TestFinished {
+                index: i as u32,
+                result: Some(TestResult::from(res.clone())),
+                phase: control.phase as i32,
+            })).await;
+            let passed = res.status == TestStatus::Passed;
             results.push(res);
+            if control.phase == Phase::Setup && !passed {
+                break;
+            }
         }
-        Ok(results)
+        results
     }
+
+    /// Runs selected tests in order, consecutive parallel tests are run
+    /// at the same time. Results are returned in the order of selected.
+    async fn execute_selected<C>(&self, capsule: &C, selected: &[usize], deadline: Option<Instant>, control: &RunControl) -> Vec<TestOutput>
+        where C: Capsule<Error = Box<dyn Error>> + Send + Sync
+    {
+        let limit = self.max_parallel
+            .map(|n| n.max(1) as usize)
+            .unwrap_or(selected.len().max(1));
+        let parallel = Semaphore::new(limit);
+        let mut results = Vec::with_capacity(selected.len());
+        let mut n = 0;
+        while n < selected.len() {
+            // Group test with all following parallel tests, if it is one itself
+            let len = if self.tests[selected[n]].parallel {
+                selected[n..].iter().take_while(|i| self.tests[**i].parallel).count()
+            } else {
+                1
+            };
+            let group = (n..n + len)
+                .map(|n| self.execute_test(capsule, n, selected, deadline, &parallel, control));
+            results.extend(join_all(group).await);
+            n += len;
+        }
+        results
+    }
+
+    /// Runs the n-th selected test once a slot is available
+    /// A test failing on retry is reported as flaky, with its failed attempts.
+    async fn execute_test<C>(&self, capsule: &C, n: usize, selected: &[usize], deadline: Option<Instant>, parallel: &Semaphore, control: &RunControl) -> TestOutput
+        where C: Capsule<Error = Box<dyn Error>> + Send + Sync
+    {
+        let i = selected[n];
+        let case = &self.tests[i];
+        let test = &case.command;
+        // Semaphores are never closed, permits are released when dropped
+        let _permit = parallel.acquire().await;
+        let _slot = match &control.slots {
+            Some(slots) => Some(slots.acquire().await),
+            None => None,
+        };
+        let timeout = self.timeout(deadline);
+        let skip_reason = if control.is_cancelled() {
+            Some("run was cancelled before test was started")
+        } else if timeout == Some(Duration::ZERO) {
+            Some("run timeout exceeded before test was started")
+        } else {
+            None
+        };
+        if let Some(reason) = skip_reason {
+            info!("{}: Skipping test {}/{}, {}", self.name.as_str(), n+1, selected.len(), reason);
+            let mut res = TestOutput::skipped(shell_words::join(test), reason).of_case(i, case);
+            res.iteration = control.iteration;
+            send_event(control.events.as_ref(), Event::Finished(TestFinished {
+                index: i as u32,
+                result: Some(TestResult::from(res.clone())),
+                phase: Phase::Test as i32,
+            })).await;
+            return res;
+        }
+        let env = self.test_env(case);
+        let mut attempts = Vec::new();
+        // Failed tests are run again as long as retries and time are left
+        let mut res = loop {
+            let attempt = attempts.len() as u32;
+            info!("{}: Running test {}/{}{}", self.name.as_str(), n+1, selected.len(),
+                if attempt > 0 { format!(", retry {}/{}", attempt, case.retries) } else { String::new() });
+            send_event(control.events.as_ref(), Event::Started(TestStarted {
+                index: i as u32,
+                command: shell_words::join(test),
+                phase: Phase::Test as i32,
+                attempt,
+                iteration: control.iteration,
+            })).await;
+            let attempt_control = RunControl { attempt, ..control.clone() };
+            let res = run_in(capsule, i, test, &env, self.timeout(deadline), &attempt_control)
+                .await
+                .of_case(i, case);
+            let retry = attempt < case.retries
+                && matches!(res.status, TestStatus::Failed | TestStatus::TimedOut | TestStatus::LimitExceeded)
+                && !control.is_cancelled()
+                && self.timeout(deadline) != Some(Duration::ZERO);
+            if !retry {
+                break res;
+            }
+            attempts.push(res);
+        };
+        if !attempts.is_empty() && res.status == TestStatus::Passed {
+            info!("{}: Test {}/{} passed after {} failed attempts", self.name.as_str(), n+1, selected.len(), attempts.len());
+            res.status = TestStatus::Flaky;
+        }
+        res.attempts = attempts;
+        res.iteration = control.iteration;
+        send_event(control.events.as_ref(), Event::Finished(TestFinished {
+            index: i as u32,
+            result: Some(TestResult::from(res.clone())),
+            phase: Phase::Test as i32,
+        })).await;
+        res
+    }
+}
+
+/// Runs command in capsule, reporting errors like a failed spawn as result
+/// of the command instead of aborting the run
+async fn run_in<C>(capsule: &C, index: usize, command: &[String], env: &TestEnv, timeout: Option<Duration>, control: &RunControl) -> TestOutput
+    where C: Capsule<Error = Box<dyn Error>> + Send + Sync
+{
+    match capsule.run_test(index, command, env, timeout, control).await {
+        Ok(res) => res,
+        Err(e) => {
+            info!("could not execute '{}': {}", shell_words::join(command), e);
+            TestOutput::error(shell_words::join(command), &e.to_string())
+        },
+    }
+}
+
+fn split_commands(commands: &[String]) -> Vec<Vec<String>> {
+    // FIXME: add proper error handling?
+    commands.iter()
+        .map(|s| shell_words::split(s).unwrap_or_default())
+        .collect()
 }
 
 impl From<crate::pb::Project> for TestProject {
     fn from(project: crate::pb::Project) -> Self {
         // FIXME: add proper error handling?
-        let tests: Vec<Vec<String>> = project.tests.iter()
-            .map(|s| shell_words::split(s).unwrap_or(Vec::new()) )
-            .collect();
+        let tests: Vec<TestCase> = if project.test_definitions.is_empty() {
+            project.tests.iter()
+                .map(|s| TestCase { command: shell_words::split(s).unwrap_or_default(), ..Default::default() })
+                .collect()
+        } else {
+            project.test_definitions.into_iter()
+                .map(TestCase::from)
+                .collect()
+        };
         TestProject {
             name: project.name,
             tests,
             hash: None,
+            test_timeout: project.test_timeout,
+            run_timeout: project.run_timeout,
+            max_parallel: project.max_parallel,
+            env: project.env.into_iter().collect(),
+            clear_env: project.clear_env,
+            pass_env: project.pass_env,
+            setup: split_commands(&project.setup),
+            teardown: split_commands(&project.teardown),
+            manifest: Manifest::default(),
+            last_update: None,
+            last_run: None,
+            capsule: project.capsule
+                .and_then(|c| c.kind)
+                .map(CapsuleChoice::from),
+            output_limit_kb: project.output_limit_kb,
+            limits: project.limits
+                .map(ResourceLimits::from)
+                .unwrap_or_default(),
+            artifacts: project.artifacts,
         }
     }
 }
 
 impl From<TestProject> for crate::pb::Project {
     fn from(t: TestProject) -> Self {
-        let tests: Vec<String> = t.tests.into_iter()
-            .map(|v| shell_words::join(v))
+        // Plain commands are sent along for clients without test definitions
+        let tests: Vec<String> = t.tests.iter()
+            .map(|case| shell_words::join(&case.command))
+            .collect();
+        let test_definitions = t.tests.into_iter()
+            .map(TestDefinition::from)
             .collect();
         crate::pb::Project {
             name: t.name,
             tests,
+            test_definitions,
+            test_timeout: t.test_timeout,
+            run_timeout: t.run_timeout,
+            max_parallel: t.max_parallel,
+            env: t.env.into_iter().collect(),
+            clear_env: t.clear_env,
+            pass_env: t.pass_env,
+            setup: t.setup.iter().map(shell_words::join).collect(),
+            teardown: t.teardown.iter().map(shell_words::join).collect(),
+            capsule: t.capsule.map(crate::pb::CapsuleOptions::from),
+            output_limit_kb: t.output_limit_kb,
+            limits: (!t.limits.is_empty()).then(|| crate::pb::ResourceLimits::from(t.limits)),
+            artifacts: t.artifacts,
         }
     }
 }
 
-async fn run_test(command: &Vec<String>, dir: &PathBuf) -> Result<TestOutput, Box<dyn Error>> {
-    let output = Command::new(&command[0])
-        // Set working directory
-        .current_dir(dir.as_path())
-        .args(&command[1..])
-        .stdin(Stdio::null())
-        .output()
-        .await?;
+/// Runs cmd for test index, streaming its output to control's events
+/// The process is killed along with its children once timeout expires or
+/// the run is cancelled. command is the test's command line used in reports.
+/// input is written to the process' stdin, which is /dev/null otherwise.
+pub async fn run_command(mut cmd: Command, input: Option<Vec<u8>>, command: String, index: usize, timeout: Option<Duration>, control: &RunControl) -> Result<TestOutput, Box<dyn Error>> {
+    let (started_at, start) = (Utc::now(), Instant::now());
+    cmd.stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Start test in its own process group, so it can be killed with all of
+    // its children
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = cmd.spawn()?;
+    let pid = child.id();
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        // Command may exit before reading all of it, stdin is closed after
+        tokio::spawn(async move {
+            let _ = stdin.write_all(&input).await;
+        });
+    }
+    // Collect output while forwarding it as events
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let mut stdout_capture = OutputCapture::new(control.output.limit);
+    let mut stderr_capture = OutputCapture::new(control.output.limit);
+    let expired = async {
+        match timeout {
+            Some(t) => tokio::time::sleep(t).await,
+            None => std::future::pending().await,
+        }
+    };
+    let (stopped, status) = {
+        let pipes = async {
+            tokio::try_join!(
+                read_output(stdout, &mut stdout_capture, index, OutputStream::Stdout, control),
+                read_output(stderr, &mut stderr_capture, index, OutputStream::Stderr, control),
+            )
+        }.fuse();
+        tokio::pin!(pipes);
+        let output = async { tokio::try_join!(&mut pipes, child.wait()) };
+        let (stopped, res) = tokio::select! {
+            res = output => (None, Some(res)),
+            _ = expired => (Some(TestStatus::TimedOut), None),
+            _ = control.cancelled() => (Some(TestStatus::Cancelled), None),
+        };
+        match res {
+            Some(res) => (stopped, res?.1),
+            None => {
+                info!("stopping test '{}' ({:?}), killing it", command.as_str(), stopped);
+                if let Some(pid) = pid {
+                    kill_process_group(pid);
+                }
+                let status = child.wait().await?;
+                // Processes which left the group may still hold the pipes,
+                // their output is dropped after a grace period
+                if !pipes.is_terminated() && tokio::time::timeout(OUTPUT_GRACE_PERIOD, &mut pipes).await.is_err() {
+                    warn!("output of test '{}' still open after killing it, dropping it", command.as_str());
+                }
+                (stopped, status)
+            },
+        }
+    };
+    let (stdout, stderr) = (stdout_capture.finish(), stderr_capture.finish());
     debug!("executed test '{}' -> {}",
-        shell_words::join(command),
-        output.status.code().map(|x| x.to_string()).unwrap_or("None".to_string()),
+        command.as_str(),
+        status.code().map(|x| x.to_string()).unwrap_or("None".to_string()),
     );
     // Return test run results
-    Ok((
-        shell_words::join(command),
-        output.status.code(),
-        output.stdout,
-        output.stderr
-    ))
+    let mut res = TestOutput::new(command, status, stdout, stderr, started_at, start);
+    if let Some(test_status) = stopped {
+        res.status = test_status;
+    }
+    Ok(res)
+}
+
+/// Sends SIGKILL to every process in the process group led by pid
+pub fn kill_process_group(pid: u32) {
+    unsafe {
+        libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+/// Reads pipe until closed, sending each chunk as output event
+/// Only head and tail of the output are kept if limited, the complete output
+/// is written to the spool file if there is one.
+async fn read_output(mut pipe: impl AsyncRead + Unpin, output: &mut OutputCapture, index: usize, stream: OutputStream, control: &RunControl) -> std::io::Result<()> {
+    let mut spool = match control.output.spool_file(control.phase, index, control.iteration, control.attempt, stream) {
+        Some(path) => open_spool(&path).await,
+        None => None,
+    };
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = pipe.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        output.push(&buf[..n]);
+        if let Some(file) = spool.as_mut() {
+            if let Err(e) = file.write_all(&buf[..n]).await {
+                // Losing the spooled output must not fail the test
+                error!("could not spool output of test {}: {}", index, e);
+                spool = None;
+            }
+        }
+        send_event(control.events.as_ref(), Event::Output(OutputChunk {
+            index: index as u32,
+            stream: stream as i32,
+            data: buf[..n].to_vec(),
+            phase: control.phase as i32,
+        })).await;
+    }
+}
+
+/// Removes spooled output of the final attempt of res
+async fn forget_spooled(control: &RunControl, res: &TestOutput) {
+    for stream in [OutputStream::Stdout, OutputStream::Stderr] {
+        if let Some(path) = control.output.spool_file(Phase::Test, res.index, res.iteration, res.attempts.len() as u32, stream) {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+}
+
+async fn open_spool(path: &Path) -> Option<tokio::fs::File> {
+    if let Some(dir) = path.parent() {
+        if let Err(e) = tokio::fs::create_dir_all(dir).await {
+            error!("could not create spool directory {}: {}", dir.display(), e);
+            return None;
+        }
+    }
+    match tokio::fs::File::create(path).await {
+        Ok(file) => Some(file),
+        Err(e) => {
+            error!("could not create spool file {}: {}", path.display(), e);
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project() -> TestProject {
+        serde_json::from_str(r#"{
+            "name": "filtered",
+            "tests": [
+                ["cargo", "test"],
+                {"command": ["cargo", "bench"], "name": "bench", "tags": ["slow"]},
+                {"command": ["make", "check"], "tags": ["slow", "make"]}
+            ]
+        }"#).unwrap()
+    }
+
+    fn filter(indices: &[u32], names: &[&str], tags: &[&str], pattern: Option<&str>) -> TestFilter {
+        TestFilter {
+            indices: indices.to_vec(),
+            names: names.iter().map(|n| n.to_string()).collect(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            pattern: pattern.map(String::from),
+        }
+    }
+
+    #[test]
+    fn selects_all_tests_without_filter() {
+        let project = project();
+        assert_eq!(project.select_tests(None).unwrap(), vec![0, 1, 2]);
+        assert_eq!(project.select_tests(Some(&TestFilter::default())).unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn selects_tests_matching_any_selector() {
+        let project = project();
+        assert_eq!(project.select_tests(Some(&filter(&[2], &[], &[], None))).unwrap(), vec![2]);
+        assert_eq!(project.select_tests(Some(&filter(&[], &["bench"], &[], None))).unwrap(), vec![1]);
+        assert_eq!(project.select_tests(Some(&filter(&[], &[], &["slow"], None))).unwrap(), vec![1, 2]);
+        assert_eq!(project.select_tests(Some(&filter(&[], &[], &[], Some("^cargo ")))).unwrap(), vec![0, 1]);
+        assert_eq!(project.select_tests(Some(&filter(&[0], &[], &["make"], None))).unwrap(), vec![0, 2]);
+        assert!(project.select_tests(Some(&filter(&[], &["missing"], &[], None))).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_positions_out_of_range() {
+        let err = project().select_tests(Some(&filter(&[3], &[], &[], None))).unwrap_err();
+        assert_eq!(err, "Project 'filtered' has no test at position 3");
+    }
+
+    /// Writes an archive of files to dir, the way increments are received
+    fn increment(dir: &Path, files: &[(&str, &str)]) -> ZipFile {
+        use std::io::Write;
+        let mut zip = ::zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (path, content) in files {
+            zip.start_file(*path, ::zip::write::FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        let path = dir.join("increment.zip");
+        std::fs::write(&path, zip.finish().unwrap().into_inner()).unwrap();
+        ZipFile::from((String::new(), path))
+    }
+
+    #[tokio::test]
+    async fn applies_increments_inside_of_project() {
+        let base_dir = std::env::temp_dir().join(format!("rt-increment-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base_dir);
+        std::fs::create_dir_all(base_dir.join("filtered")).unwrap();
+        std::fs::write(base_dir.join("filtered/a.txt"), "a").unwrap();
+        let mut project = project();
+        project.hash = Some(String::from("h0"));
+        project.manifest.insert(String::from("a.txt"), crate::hash::hash("a").await);
+
+        let err = project.apply_increment(increment(&base_dir, &[("b.txt", "b")]), String::from("h0"), String::from("h1"), vec![String::from("../a.txt")], &base_dir).await.unwrap_err();
+        assert_eq!(err, "Invalid path '../a.txt' in deleted files");
+        let err = project.apply_increment(increment(&base_dir, &[("../evil.txt", "evil")]), String::from("h0"), String::from("h1"), vec![], &base_dir).await.unwrap_err();
+        assert_eq!(err, "Invalid path '../evil.txt' in increment");
+        assert!(!base_dir.join("evil.txt").exists());
+        assert_eq!(project.hash.as_deref(), Some("h0"));
+
+        let mut expected = Manifest::default();
+        expected.insert(String::from("b.txt"), crate::hash::hash("b").await);
+        let newhash = expected.tree_hash().await;
+        project.apply_increment(increment(&base_dir, &[("b.txt", "b")]), String::from("h0"), newhash.clone(), vec![String::from("a.txt")], &base_dir).await.unwrap();
+        assert_eq!(project.hash, Some(newhash));
+        assert!(!base_dir.join("filtered/a.txt").exists());
+        assert_eq!(std::fs::read_to_string(base_dir.join("filtered/b.txt")).unwrap(), "b");
+        // Increments are removed once applied or rejected
+        assert!(!base_dir.join("increment.zip").exists());
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    /// Runs all tests of project after setup, returning their statuses
+    async fn statuses_after_setup(project: TestProject, cancelled: bool) -> Vec<TestStatus> {
+        let mut capsule = TransparentCapsule::default();
+        capsule.encapsulate(String::from("setup"), &std::env::temp_dir(), ResourceLimits::default()).await.unwrap();
+        let (_cancel, cancel_rx) = watch::channel(cancelled);
+        let control = RunControl { cancel: Some(cancel_rx), ..RunControl::default() };
+        let schedule = Schedule { times: 1, until_failure: false, seed: None };
+        let output = project.execute_phases(&capsule, &[0, 1], schedule, &control).await;
+        output.tests.iter().map(|t| t.status).collect()
+    }
+
+    fn with_setup(setup: &[&str], run_timeout: Option<u64>) -> TestProject {
+        let mut project = project();
+        project.setup = vec![setup.iter().map(|s| s.to_string()).collect()];
+        project.run_timeout = run_timeout;
+        project
+    }
+
+    #[tokio::test]
+    async fn reports_why_setup_did_not_complete() {
+        let failed = statuses_after_setup(with_setup(&["false"], None), false).await;
+        assert_eq!(failed, vec![TestStatus::SetupFailed; 2]);
+        let cancelled = statuses_after_setup(with_setup(&["true"], None), true).await;
+        assert_eq!(cancelled, vec![TestStatus::Cancelled; 2]);
+        let timed_out = statuses_after_setup(with_setup(&["true"], Some(0)), false).await;
+        assert_eq!(timed_out, vec![TestStatus::TimedOut; 2]);
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let err = project().select_tests(Some(&filter(&[], &[], &[], Some("(")))).unwrap_err();
+        assert!(err.starts_with("Invalid test pattern"), "{}", err);
+    }
 }
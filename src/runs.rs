@@ -0,0 +1,269 @@
+use std::{collections::HashMap, path::PathBuf, sync::{Arc, atomic::{AtomicU64, Ordering}}};
+
+use log::{error, info};
+use tokio::{sync::{RwLock, Semaphore, mpsc, watch}, task::JoinHandle};
+
+use crate::artifacts;
+use crate::capsule::CapsuleConfig;
+use crate::pb::{Phase, RunInfo, RunRequest, RunState, TestResult, TestResults, TestStats, TestStatus, test_event::Event};
+use crate::output::OutputSettings;
+use crate::project::{EVENT_BUFFER, EventSender, RunControl, RunOutput, TestProject, forward_event};
+
+/// Number of ended runs kept around for lookups
+const MAX_ENDED_RUNS: usize = 100;
+
+/// Removes test output from run, leaving only results
+pub fn strip_output(info: &mut RunInfo) {
+    for result in info.results.iter_mut().flat_map(|r| r.setup.iter_mut().chain(r.results.iter_mut()).chain(r.teardown.iter_mut())) {
+        result.stdout.clear();
+        result.stderr.clear();
+        for attempt in result.attempts.iter_mut() {
+            attempt.stdout.clear();
+            attempt.stderr.clear();
+        }
+    }
+}
+
+/// Sums up results of repeated tests, in order of their first result
+fn test_stats(results: &[TestResult]) -> Vec<TestStats> {
+    let mut stats: Vec<TestStats> = Vec::new();
+    for result in results {
+        let n = match stats.iter().position(|s| s.index == result.index) {
+            Some(n) => n,
+            None => {
+                stats.push(TestStats { index: result.index, name: result.name.clone(), ..Default::default() });
+                stats.len() - 1
+            },
+        };
+        let s = &mut stats[n];
+        s.runs += 1;
+        if result.success {
+            s.passed += 1;
+        }
+        if result.status == TestStatus::Flaky as i32 {
+            s.flaky += 1;
+        }
+    }
+    stats
+}
+
+struct RunJob {
+    info: RunInfo,
+    cancel: watch::Sender<bool>,
+}
+
+fn active_run(runs: &HashMap<String, RunJob>, project: &str) -> Option<String> {
+    runs.values()
+        .find(|j| j.info.project == project && j.info.state == RunState::Running as i32)
+        .map(|j| j.info.run_id.clone())
+}
+
+/// Keeps track of all test runs started by the server
+///
+/// Runs are executed in their own task, so they continue when the client
+/// that started them disconnects
+pub struct RunRegistry {
+    runs: RwLock<HashMap<String, RunJob>>,
+    counter: AtomicU64,
+    /// Shared by all runs to limit tests running at the same time
+    slots: Arc<Semaphore>,
+}
+
+impl RunRegistry {
+    /// Creates registry running at most max_parallel tests at the same time
+    pub fn new(max_parallel: usize) -> Self {
+        RunRegistry {
+            runs: RwLock::new(HashMap::new()),
+            counter: AtomicU64::new(0),
+            slots: Arc::new(Semaphore::new(max_parallel.max(1))),
+        }
+    }
+
+    fn next_id(&self) -> String {
+        let n = self.counter.fetch_add(1, Ordering::SeqCst);
+        format!("{}-{}", chrono::Utc::now().format("%Y%m%d%H%M%S"), n)
+    }
+
+    /// Starts running the tests of project selected by request in the background
+    /// Events are forwarded to events, if supplied. Output is spooled to a
+    /// subdirectory of output's spool_dir named after the run, artifacts are
+    /// collected into it as well. The returned
+    /// handle resolves to the complete results once the run has ended.
+    /// Fails if the project is still running, as runs share its directory.
+    pub async fn start(self: &Arc<Self>, project: TestProject, mut request: RunRequest, base_dir: PathBuf, capsules: Arc<CapsuleConfig>, output: OutputSettings, events: Option<EventSender>) -> Result<(String, JoinHandle<Result<TestResults, String>>), String> {
+        let run_id = self.next_id();
+        // Seed is reported right away, so even aborted runs can be replayed
+        if request.shuffle && request.seed.is_none() {
+            request.seed = Some(rand::random());
+        }
+        let run_dir = output.spool_dir.map(|dir| dir.join(&run_id));
+        let output = OutputSettings {
+            artifact_dir: run_dir.as_ref().map(|dir| dir.join(artifacts::ARTIFACT_DIR)),
+            spool_dir: run_dir,
+            ..output
+        };
+        let timestamp = chrono::Utc::now()
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let (name, hash) = project.get_tuple();
+        let (cancel, cancel_rx) = watch::channel(false);
+        let info = RunInfo {
+            run_id: run_id.clone(),
+            project: name.clone(),
+            state: RunState::Running as i32,
+            started_at: timestamp.clone(),
+            finished_at: None,
+            results: Some(TestResults {
+                name,
+                hash,
+                timestamp,
+                results: Vec::new(),
+                run_id: run_id.clone(),
+                setup: Vec::new(),
+                teardown: Vec::new(),
+                iterations: 0,
+                stats: Vec::new(),
+                seed: request.seed,
+                artifacts: Vec::new(),
+            }),
+            error: None,
+        };
+        {
+            let mut runs = self.runs.write().await;
+            if let Some(active) = active_run(&runs, project.get_name()) {
+                return Err(format!("Project '{}' is already running in run {}", project.get_name(), active));
+            }
+            runs.insert(run_id.clone(), RunJob { info, cancel });
+        }
+        info!("started run {} for project {}", run_id.as_str(), project.get_name());
+
+        let registry = self.clone();
+        let slots = self.slots.clone();
+        let id = run_id.clone();
+        let handle = tokio::spawn(async move {
+            let (tx, mut rx) = mpsc::channel(EVENT_BUFFER);
+            let control = RunControl { events: Some(tx), cancel: Some(cancel_rx), slots: Some(slots), phase: Phase::Test, attempt: 0, iteration: 0, output };
+            let run = async {
+                project.execute_all_tests(&base_dir, &request, &capsules, control)
+                    .await
+                    .map_err(|e| e.to_string())
+            };
+            // Record results as tests finish, so they can be looked up early
+            let track = async {
+                let mut events = events;
+                while let Some(event) = rx.recv().await {
+                    if let Some(Event::Finished(finished)) = &event.event {
+                        if let Some(result) = finished.result.clone() {
+                            registry.add_result(&id, finished.phase(), result).await;
+                        }
+                    }
+                    if let Some(tx) = &events {
+                        if !forward_event(tx, event).await {
+                            // Subscriber left or fell too far behind
+                            events = None;
+                        }
+                    }
+                }
+            };
+            let (res, _) = tokio::join!(run, track);
+            registry.end(&id, res).await
+        });
+        Ok((run_id, handle))
+    }
+
+    async fn add_result(&self, run_id: &str, phase: Phase, result: TestResult) {
+        if let Some(job) = self.runs.write().await.get_mut(run_id) {
+            if let Some(results) = job.info.results.as_mut() {
+                match phase {
+                    Phase::Setup => results.setup.push(result),
+                    Phase::Test => results.results.push(result),
+                    Phase::Teardown => results.teardown.push(result),
+                }
+            }
+        }
+    }
+
+    /// Stores final outcome of a run and returns its complete results
+    async fn end(&self, run_id: &str, res: Result<RunOutput, String>) -> Result<TestResults, String> {
+        let mut runs = self.runs.write().await;
+        let job = runs.get_mut(run_id)
+            .ok_or(format!("Run '{}' does not exist", run_id))?;
+        job.info.finished_at = Some(chrono::Utc::now()
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true));
+        let res = match res {
+            Ok(output) => {
+                let state = if *job.cancel.borrow() { RunState::Cancelled } else { RunState::Finished };
+                job.info.state = state as i32;
+                let mut test_results = job.info.results.clone().unwrap_or_default();
+                test_results.setup = output.setup.into_iter().map(TestResult::from).collect();
+                test_results.results = output.tests.into_iter().map(TestResult::from).collect();
+                test_results.teardown = output.teardown.into_iter().map(TestResult::from).collect();
+                test_results.artifacts = output.artifacts;
+                if let Some(iterations) = output.iterations {
+                    test_results.iterations = iterations;
+                    test_results.stats = test_stats(&test_results.results);
+                }
+                job.info.results = Some(test_results.clone());
+                info!("run {} ended ({:?})", run_id, state);
+                Ok(test_results)
+            },
+            Err(e) => {
+                error!("error occured while running test: {}", e);
+                job.info.state = RunState::Failed as i32;
+                job.info.error = Some(e.clone());
+                Err(e)
+            },
+        };
+        // Forget oldest ended runs
+        let mut ended: Vec<(String, String)> = runs.values()
+            .filter(|j| j.info.state != RunState::Running as i32)
+            .map(|j| (j.info.started_at.clone(), j.info.run_id.clone()))
+            .collect();
+        if ended.len() > MAX_ENDED_RUNS {
+            let n = ended.len() - MAX_ENDED_RUNS;
+            ended.sort();
+            for (_, id) in ended.into_iter().take(n) {
+                runs.remove(&id);
+            }
+        }
+        res
+    }
+
+    pub async fn get(&self, run_id: &str) -> Option<RunInfo> {
+        self.runs.read().await
+            .get(run_id)
+            .map(|j| j.info.clone())
+    }
+
+    /// Returns id of the run of project that is still running, if any
+    pub async fn active(&self, project: &str) -> Option<String> {
+        active_run(&*self.runs.read().await, project)
+    }
+
+    /// Lists runs ordered by start time, without test output
+    pub async fn list(&self, project: Option<&str>) -> Vec<RunInfo> {
+        let mut runs: Vec<RunInfo> = self.runs.read().await
+            .values()
+            .filter(|j| project.map(|p| j.info.project == p).unwrap_or(true))
+            .map(|j| {
+                let mut info = j.info.clone();
+                strip_output(&mut info);
+                info
+            })
+            .collect();
+        runs.sort_by(|a, b| (&a.started_at, &a.run_id).cmp(&(&b.started_at, &b.run_id)));
+        runs
+    }
+
+    /// Requests a running run to stop
+    pub async fn cancel(&self, run_id: &str) -> Result<(), String> {
+        let runs = self.runs.read().await;
+        let job = runs.get(run_id)
+            .ok_or(format!("Run '{}' does not exist", run_id))?;
+        if job.info.state != RunState::Running as i32 {
+            return Err(format!("Run '{}' has already ended", run_id));
+        }
+        info!("cancelling run {}", run_id);
+        let _ = job.cancel.send(true);
+        Ok(())
+    }
+}
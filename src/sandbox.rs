@@ -0,0 +1,330 @@
+use std::{ffi::{CStr, CString}, io::{Error, Result}, os::unix::ffi::OsStrExt, path::Path};
+
+use tokio::process::Command;
+
+/// Sets read-only flag of mounts, see mount_setattr(2)
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+/// Applies mount attributes to a whole mount tree
+const AT_RECURSIVE: libc::c_uint = 0x8000;
+
+/// Argument of mount_setattr(2), missing from libc
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// Argument of the SIOCSIFFLAGS ioctl, missing from libc
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; 16],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// Confines commands to unprivileged user, mount and PID namespaces
+///
+/// Everything but dir, which is kept writable, is mounted read-only, /tmp is
+/// replaced by an empty tmpfs unless it contains dir. Commands only see their own processes and
+/// can only reach the loopback interface if the network is isolated.
+#[derive(Clone)]
+pub struct Sandbox {
+    dir: CString,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    isolate_network: bool,
+    private_tmp: bool,
+}
+
+impl Sandbox {
+    pub fn new(dir: &Path, isolate_network: bool) -> Result<Self> {
+        let dir = std::fs::canonicalize(dir)?;
+        // Commands keep their ids, so they are unprivileged after exec
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        Ok(Sandbox {
+            dir: CString::new(dir.as_os_str().as_bytes())?,
+            uid_map: format!("{} {} 1", uid, uid).into_bytes(),
+            gid_map: format!("{} {} 1", gid, gid).into_bytes(),
+            isolate_network,
+            // tmpfs would hide dir
+            private_tmp: !dir.starts_with("/tmp"),
+        })
+    }
+
+    /// Makes the process started by cmd enter new namespaces before exec
+    /// The started process stays outside and forks an init process for the
+    /// PID namespace, which forks the command in turn. Both wait for their
+    /// child and pass its exit status on. All processes of the namespace
+    /// are killed once the command exits or the started process dies.
+    pub fn apply_to(&self, cmd: &mut Command) {
+        let sandbox = self.clone();
+        // Only async-signal-safe calls are allowed between fork and exec
+        unsafe {
+            cmd.pre_exec(move || sandbox.enter());
+        }
+    }
+
+    unsafe fn enter(&self) -> Result<()> {
+        let mut flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+        if self.isolate_network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        check(libc::unshare(flags))?;
+        write_file(c"/proc/self/setgroups", b"deny")?;
+        write_file(c"/proc/self/uid_map", &self.uid_map)?;
+        write_file(c"/proc/self/gid_map", &self.gid_map)?;
+        // Killing the process group of the started process has to reach
+        // init as well
+        check(libc::setpgid(0, 0))?;
+        // Init reports the wait status of the command through it
+        let mut status_pipe = [0; 2];
+        check(libc::pipe2(status_pipe.as_mut_ptr(), libc::O_CLOEXEC))?;
+        match libc::fork() {
+            -1 => Err(Error::last_os_error()),
+            0 => {
+                libc::close(status_pipe[0]);
+                check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+                self.setup_mounts()?;
+                if self.isolate_network {
+                    loopback_up()?;
+                }
+                match libc::fork() {
+                    -1 => Err(Error::last_os_error()),
+                    0 => {
+                        libc::close(status_pipe[1]);
+                        Ok(())
+                    },
+                    pid => run_init(pid, status_pipe[1]),
+                }
+            },
+            pid => {
+                libc::close(status_pipe[1]);
+                wait_and_exit(pid, status_pipe[0])
+            },
+        }
+    }
+
+    unsafe fn setup_mounts(&self) -> Result<()> {
+        let root = c"/".as_ptr();
+        let dir = self.dir.as_ptr();
+        // Don't propagate any of the following mounts to the host
+        check(libc::mount(std::ptr::null(), root, std::ptr::null(), libc::MS_REC | libc::MS_PRIVATE, std::ptr::null()))?;
+        // Separate mount for dir, so it can be writable on its own
+        check(libc::mount(dir, dir, std::ptr::null(), libc::MS_BIND | libc::MS_REC, std::ptr::null()))?;
+        set_read_only(root, AT_RECURSIVE, true)?;
+        set_read_only(dir, 0, false)?;
+        if self.private_tmp {
+            check(libc::mount(
+                c"tmpfs".as_ptr(),
+                c"/tmp".as_ptr(),
+                c"tmpfs".as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                std::ptr::null(),
+            ))?;
+        }
+        // Show only processes of the namespace
+        check(libc::mount(
+            c"proc".as_ptr(),
+            c"/proc".as_ptr(),
+            c"proc".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            std::ptr::null(),
+        ))?;
+        // Working directory still points into the read-only mount
+        check(libc::chdir(dir))
+    }
+}
+
+fn check(res: libc::c_int) -> Result<()> {
+    if res == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+unsafe fn write_file(path: &CStr, data: &[u8]) -> Result<()> {
+    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+    check(fd)?;
+    let written = libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+    libc::close(fd);
+    if written != data.len() as isize {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+unsafe fn set_read_only(path: *const libc::c_char, flags: libc::c_uint, read_only: bool) -> Result<()> {
+    let attr = MountAttr {
+        attr_set: if read_only { MOUNT_ATTR_RDONLY } else { 0 },
+        attr_clr: if read_only { 0 } else { MOUNT_ATTR_RDONLY },
+        propagation: 0,
+        userns_fd: 0,
+    };
+    let res = libc::syscall(libc::SYS_mount_setattr, libc::AT_FDCWD, path, flags, &attr as *const MountAttr, std::mem::size_of::<MountAttr>());
+    check(res as libc::c_int)
+}
+
+/// Brings up the loopback interface of a new network namespace
+unsafe fn loopback_up() -> Result<()> {
+    let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+    check(fd)?;
+    let mut req = IfReq { name: [0; 16], flags: 0, _pad: [0; 22] };
+    req.name[0] = b'l' as libc::c_char;
+    req.name[1] = b'o' as libc::c_char;
+    req.flags = libc::IFF_UP as libc::c_short;
+    let res = libc::ioctl(fd, libc::SIOCSIFFLAGS, &req as *const IfReq);
+    libc::close(fd);
+    check(res)
+}
+
+/// Closes all file descriptors but keep
+/// Pipes must not be kept open by the waiting processes, otherwise the
+/// server waits for them instead of the command.
+unsafe fn close_all_but(keep: libc::c_int) {
+    let max = libc::c_uint::MAX;
+    if libc::syscall(libc::SYS_close_range, 0, keep - 1, 0) != 0
+        || libc::syscall(libc::SYS_close_range, keep + 1, max, 0) != 0
+    {
+        for fd in 0..libc::sysconf(libc::_SC_OPEN_MAX) as libc::c_int {
+            if fd != keep {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+/// Waits for pid, retrying if interrupted
+unsafe fn wait_for(pid: libc::pid_t, status: &mut libc::c_int) -> libc::pid_t {
+    loop {
+        let res = libc::waitpid(pid, status, 0);
+        if res != -1 || *libc::__errno_location() != libc::EINTR {
+            return res;
+        }
+    }
+}
+
+/// Reaps processes of the namespace until the command exits, then reports
+/// its wait status through status_pipe
+/// Exiting as init kills all processes left in the namespace.
+unsafe fn run_init(command: libc::pid_t, status_pipe: libc::c_int) -> ! {
+    close_all_but(status_pipe);
+    let mut status = 0;
+    loop {
+        match wait_for(-1, &mut status) {
+            -1 => libc::_exit(127),
+            pid if pid == command => break,
+            _ => (),
+        }
+    }
+    libc::write(status_pipe, &status as *const libc::c_int as *const libc::c_void, std::mem::size_of::<libc::c_int>());
+    libc::_exit(0)
+}
+
+/// Waits for init and exits the same way as the command
+unsafe fn wait_and_exit(init: libc::pid_t, status_pipe: libc::c_int) -> ! {
+    close_all_but(status_pipe);
+    let mut status = 0;
+    let size = std::mem::size_of::<libc::c_int>();
+    let mut reported = -1;
+    while reported == -1 {
+        reported = libc::read(status_pipe, &mut status as *mut libc::c_int as *mut libc::c_void, size);
+        if reported == -1 && *libc::__errno_location() != libc::EINTR {
+            break;
+        }
+    }
+    let mut init_status = 0;
+    wait_for(init, &mut init_status);
+    if reported != size as isize {
+        // Init died before the command exited
+        status = init_status;
+    }
+    if libc::WIFSIGNALED(status) {
+        // Die from the same signal, so it's reported for the command
+        let sig = libc::WTERMSIG(status);
+        let mut mask = std::mem::zeroed();
+        libc::sigemptyset(&mut mask);
+        libc::sigprocmask(libc::SIG_SETMASK, &mask, std::ptr::null_mut());
+        libc::signal(sig, libc::SIG_DFL);
+        libc::kill(libc::getpid(), sig);
+        libc::_exit(128 + sig);
+    }
+    libc::_exit(libc::WEXITSTATUS(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::unix::process::ExitStatusExt, path::PathBuf, process::Output};
+
+    /// Project directory, within the checkout
+    fn project(name: &str) -> PathBuf {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join(format!("rt-sandbox-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs script in a sandbox for dir, None if namespaces can't be created
+    /// by unprivileged users here
+    async fn run(dir: &Path, isolate_network: bool, script: &str) -> Option<Output> {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script).current_dir(dir);
+        Sandbox::new(dir, isolate_network).unwrap().apply_to(&mut cmd);
+        match cmd.output().await {
+            Ok(output) => Some(output),
+            Err(e) => {
+                eprintln!("skipping sandbox test, namespaces are not available: {}", e);
+                None
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn only_dir_and_tmp_are_writable() {
+        let dir = project("writable");
+        let outside = dir.with_file_name(format!("rt-sandbox-outside-{}", std::process::id()));
+        let tmp_file = std::env::temp_dir().join(format!("rt-sandbox-{}", std::process::id()));
+        // /tmp is only replaced if the checkout is somewhere else
+        let private_tmp = Sandbox::new(&dir, false).unwrap().private_tmp;
+        let script = format!(
+            "echo inside > inside.txt && ! echo outside > {outside} && {tmp_check} echo tmp > {tmp}",
+            outside = outside.display(),
+            tmp_check = if private_tmp { "" } else { "!" },
+            tmp = tmp_file.display(),
+        );
+        if let Some(output) = run(&dir, false, &script).await {
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+            assert!(String::from_utf8_lossy(&output.stderr).contains("Read-only file system"));
+            assert_eq!(std::fs::read_to_string(dir.join("inside.txt")).unwrap(), "inside\n");
+            assert!(!outside.exists());
+            // Files written to /tmp stay in the sandbox
+            assert!(!tmp_file.exists());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn isolates_network() {
+        let dir = project("network");
+        let script = "tail -n +3 /proc/net/dev | cut -d: -f1 | tr -d ' '";
+        if let Some(output) = run(&dir, true, script).await {
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "lo\n");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn relays_exit_status() {
+        let dir = project("status");
+        if let Some(output) = run(&dir, false, "exit 3").await {
+            assert_eq!(output.status.code(), Some(3));
+        }
+        if let Some(output) = run(&dir, true, "kill -TERM $$").await {
+            assert_eq!(output.status.signal(), Some(libc::SIGTERM));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
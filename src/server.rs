@@ -1,9 +1,10 @@
-use std::{collections::HashMap, error::Error, net::{IpAddr, SocketAddr}, path::{Path, PathBuf}, str::FromStr, sync::Arc};
+use std::{collections::{HashMap, hash_map::Entry}, error::Error, net::{IpAddr, SocketAddr}, path::{Path, PathBuf}, str::FromStr, sync::Arc};
 
 use log::{debug, error, info, warn};
-use remote_test::{pb::{Project, ProjectIdentifier, ProjectIncrement, ProjectUpdate, RegisterResponse, TestResult, TestResults, UpdateResponse, remote_server::{Remote, RemoteServer}}, project::TestProject, zip::ZipFile};
-use tokio::{fs::DirBuilder, sync::RwLock};
-use tonic::{Request, Response, Status, transport::Server};
+use remote_test::{artifacts, capsule::{CapsuleChoice, CapsuleConfig, ContainerCapsule, ContainerOptions, KubernetesCapsule, PodOptions, SandboxOptions}, pb::{ArtifactChunk, ArtifactRequest, CancelResponse, ListProjectsRequest, ListRunsRequest, Project, ProjectChunk, ProjectIdentifier, ProjectIncrement, ProjectInfo, ProjectList, ProjectUpdate, RegisterResponse, OutputChunk, OutputRequest, RunHistory, RunHistoryRequest, RunIdentifier, RunInfo, RunList, RunRequest, RunState, TestEvent, TestResults, UpdateResponse, UploadIdentifier, UploadStatus, remote_server::{Remote, RemoteServer}, test_event::Event}, history, output::{self, OutputSettings}, project::{EVENT_BUFFER, EventSender, TestProject}, runs::RunRegistry, zip::{ZipFile, ZipUpload}};
+use tokio::{fs::DirBuilder, io::AsyncReadExt, sync::{RwLock, mpsc}, task::JoinHandle};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming, transport::Server};
 
 macro_rules! response {
     ($x:expr) => {
@@ -24,18 +25,44 @@ async fn flush_to_file(projects: Arc<RwLock<HashMap<String, TestProject>>>) -> R
     Ok(())
 }
 
+/// Starts flush of projects to file in a new task
+fn spawn_flush(projects: Arc<RwLock<HashMap<String, TestProject>>>) {
+    tokio::spawn(async {
+        let res = flush_to_file(projects).await;
+        // Log possible errors
+        if let Err(e) = res {
+            let mut s = String::default();
+            if let Some(src) = e.source() {
+                s = format!(" caused by {}", src);
+            }
+            error!("projects backup flush failed: {}{}", e, s);
+        } else {
+            info!("flushed projects to backup");
+        }
+    });
+}
+
 pub struct RemoteServerContext {
     base_dir: PathBuf,
     zip_cache_dir: PathBuf,
     projects: Arc<RwLock<HashMap<String, TestProject>>>,
+    runs: Arc<RunRegistry>,
+    history: Arc<history::RunHistory>,
+    capsules: Arc<CapsuleConfig>,
+    /// KB of output kept per stream unless projects set their own limit
+    output_limit_kb: u64,
 }
 
 impl RemoteServerContext {
-    pub fn new(base_dir: PathBuf, zip_cache_dir: PathBuf) -> Self {
+    pub fn new(base_dir: PathBuf, zip_cache_dir: PathBuf, history: history::RunHistory, capsules: CapsuleConfig, max_parallel: usize, output_limit_kb: u64) -> Self {
         RemoteServerContext {
             base_dir,
             zip_cache_dir,
             projects: Arc::new(RwLock::new(HashMap::new())),
+            runs: Arc::new(RunRegistry::new(max_parallel)),
+            history: Arc::new(history),
+            capsules: Arc::new(capsules),
+            output_limit_kb,
         }
     }
 
@@ -46,23 +73,97 @@ impl RemoteServerContext {
         }
     }
 
-    // Starts flush of projects to file, but does not wait for it to finish
-    fn flush_projects(&self) {
-        // Copy projects ref for new async task
+    /// Looks up a run among current and stored runs
+    async fn find_run(&self, run_id: &str) -> Result<RunInfo, Status> {
+        let info = match self.runs.get(run_id).await {
+            Some(info) => Some(info),
+            None => self.history.get(run_id)
+                .await
+                .map_err(|e| Status::internal(format!("Could not read run history: {}", e)))?,
+        };
+        info.ok_or_else(|| Status::not_found(format!("Run '{}' does not exist", run_id)))
+    }
+
+    /// Fails if project has a run in progress, its directory must not be
+    /// changed until the run has ended
+    async fn check_idle(&self, project: &str) -> Result<(), String> {
+        match self.runs.active(project).await {
+            Some(run_id) => Err(format!("Project '{}' is busy with run {}, try again once it has ended", project, run_id)),
+            None => Ok(()),
+        }
+    }
+
+    /// Starts test run for a copy of the project, so no lock is held while
+    /// tests are running
+    async fn spawn_run(&self, request: RunRequest, events: Option<EventSender>) -> Result<(String, JoinHandle<Result<TestResults, String>>), Status> {
+        let project = request.name.as_str();
+        // Updates must wait until the run is registered
+        let projects = self.projects.read().await;
+        let test_project = projects
+            .get(project)
+            .cloned()
+            .ok_or_else(|| {
+                debug!("project {} does not exist", project);
+                Status::invalid_argument(format!("Project '{}' does not exist!", project))
+            })?;
+        // Reject invalid filters before a run is created
+        test_project.select_tests(request.filter.as_ref())
+            .map_err(Status::invalid_argument)?;
+        if request.repeat == Some(0) {
+            return Err(Status::invalid_argument("Tests must be repeated at least once"));
+        }
+        match test_project.capsule(&self.capsules) {
+            CapsuleChoice::Kubernetes(_) if self.capsules.kubernetes.is_none() => {
+                return Err(Status::failed_precondition("Kubernetes capsule is not configured on this server"));
+            },
+            CapsuleChoice::Container(_) if self.capsules.container.is_none() => {
+                return Err(Status::failed_precondition("Container capsule is not configured on this server"));
+            },
+            _ => (),
+        }
+        let name = project.to_string();
+        let output = OutputSettings {
+            limit: test_project.output_limit(self.output_limit_kb),
+            spool_dir: Some(self.history.output_dir(project)),
+            // Placed within the run's spool dir once the run is started
+            artifact_dir: None,
+        };
+        let (run_id, handle) = self.runs.start(test_project, request, self.base_dir.clone(), self.capsules.clone(), output, events)
+            .await
+            .map_err(Status::failed_precondition)?;
+        drop(projects);
+        // Remember outcome with the project once the run has ended
         let projects = self.projects.clone();
-        tokio::spawn(async {
-            let res = flush_to_file(projects).await;
-            // Log possible errors
-            if let Err(e) = res {
-                let mut s = String::default();
-                if let Some(src) = e.source() {
-                    s = format!(" caused by {}", src.to_string());
+        let runs = self.runs.clone();
+        let history = self.history.clone();
+        let id = run_id.clone();
+        let handle = tokio::spawn(async move {
+            let res = handle.await
+                .map_err(|e| format!("Test run failed: {}", e))
+                .and_then(|r| r);
+            let info = runs.get(&id).await;
+            // Keep run with its output beyond the lifetime of the registry
+            if let Some(info) = &info {
+                if let Err(e) = history.store(info).await {
+                    error!("could not store run {} in history: {}", id.as_str(), e);
                 }
-                error!("projects backup flush failed: {}{}", e, s);
-            } else {
-                info!("flushed projects to backup");
             }
+            let state = info
+                .and_then(|info| RunState::from_i32(info.state))
+                .unwrap_or(RunState::Failed);
+            if let Some(p) = projects.write().await.get_mut(&name) {
+                p.record_run(id, state, res.as_ref().ok());
+            }
+            spawn_flush(projects);
+            res
         });
+        Ok((run_id, handle))
+    }
+
+    // Starts flush of projects to file, but does not wait for it to finish
+    fn flush_projects(&self) {
+        // Copy projects ref for new async task
+        spawn_flush(self.projects.clone());
     }
 
 }
@@ -79,21 +180,24 @@ impl Remote for RemoteServerContext {
         let mut p = self.projects.write().await;
 
         // Insert new project if name does not yet exist
-        if p.contains_key(&name) {
-            debug!("project {} already exists", name.as_str());
-            response!(RegisterResponse {
-                success: false,
-                error: Some(format!("Project with name '{}' already exists!", name.as_str())),
-            })
-        } else {
-            info!("successfully registered project {}", name.as_str());
-            let _ = p.insert(name, project);
-            // Flush after insert
-            self.flush_projects();
-            response!(RegisterResponse {
-                success: true,
-                error: None,
-            })
+        match p.entry(name) {
+            Entry::Occupied(e) => {
+                debug!("project {} already exists", e.key().as_str());
+                response!(RegisterResponse {
+                    success: false,
+                    error: Some(format!("Project with name '{}' already exists!", e.key().as_str())),
+                })
+            },
+            Entry::Vacant(e) => {
+                info!("successfully registered project {}", e.key().as_str());
+                e.insert(project);
+                // Flush after insert
+                self.flush_projects();
+                response!(RegisterResponse {
+                    success: true,
+                    error: None,
+                })
+            },
         }
     }
 
@@ -106,6 +210,10 @@ impl Remote for RemoteServerContext {
 
         let maybe_project = {
             let mut p = self.projects.write().await;
+            if let Err(e) = self.check_idle(&project_name).await {
+                debug!("could not unregister project {}: {}", project_name.as_str(), e.as_str());
+                return response!(RegisterResponse { success: false, error: Some(e) });
+            }
             // Try to remove project, if it exists
             let res = p.remove(&project_name);
             if res.is_some() {
@@ -124,10 +232,14 @@ impl Remote for RemoteServerContext {
                 if dir.exists() && dir.is_dir() {
                     debug!("removing project folder {:?}", dir.as_os_str());
                     if let Err(e) = tokio::fs::remove_dir_all(dir.as_path()).await {
-                        error!("could not clear directory {}", e.to_string());
+                        error!("could not clear directory {}", e);
                         error = Some(format!("Could not clear directory: {}", e));
                     };
                 }
+                if let Err(e) = self.history.remove_project(&project_name).await {
+                    error!("could not clear run history {}", e);
+                    error = Some(format!("Could not clear run history: {}", e));
+                }
                 info!("successfully unregistered project {}", project_name.as_str());
                 response!(RegisterResponse { success: true, error })
             },
@@ -138,6 +250,29 @@ impl Remote for RemoteServerContext {
         }
     }
 
+    async fn update_project_config(
+        &self,
+        request: Request<Project>
+    ) -> Result<Response<RegisterResponse>,Status> {
+        let config: TestProject = request.into_inner().into();
+        let name = config.get_name().to_string();
+        debug!("received UpdateProjectConfig request for project '{}'", name.as_str());
+        let mut p = self.projects.write().await;
+        match p.get_mut(&name) {
+            Some(project) => {
+                project.update_config(config);
+                // Flush after config change
+                self.flush_projects();
+                info!("updated config of project {}", name.as_str());
+                response!(RegisterResponse { success: true, error: None })
+            },
+            None => {
+                debug!("project {} does not exist", name.as_str());
+                response!(RegisterResponse { success: false, error: Some(format!("Project '{}' does not exist", name.as_str())) })
+            },
+        }
+    }
+
     async fn update_project(
         &self,
         request: Request<ProjectUpdate>
@@ -148,6 +283,15 @@ impl Remote for RemoteServerContext {
         let mut p = self.projects.write().await;
         match p.get_mut(&update.name) {
             Some(project) => {
+                if let Err(e) = self.check_idle(&update.name).await {
+                    debug!("could not apply update to project {}: {}", update.name.as_str(), e.as_str());
+                    return response!(UpdateResponse {
+                        project: update.name,
+                        hash: update.hash,
+                        success: false,
+                        error: Some(e),
+                    });
+                }
                 debug!("preparing update for project {}", update.name.as_str());
                 // Store content to local file
                 let zipfile = ZipFile::from_contents(update.blob, &self.zip_cache_dir)
@@ -170,7 +314,7 @@ impl Remote for RemoteServerContext {
                         })
                     },
                     Err(e) => {
-                        debug!("could not apply update to project {}: {}", update.name.as_str(), e.to_string());
+                        debug!("could not apply update to project {}: {}", update.name.as_str(), e);
                         response!(UpdateResponse {
                             project: update.name,
                             hash: update.hash,
@@ -193,55 +337,445 @@ impl Remote for RemoteServerContext {
         }
     }
     
+    async fn upload_project(
+        &self,
+        request: Request<Streaming<ProjectChunk>>
+    ) -> Result<Response<UpdateResponse>,Status> {
+        let mut stream = request.into_inner();
+        let mut upload: Option<(ZipUpload, ProjectChunk)> = None;
+        // Write chunks to the cache dir as they arrive
+        while let Some(chunk) = stream.message().await? {
+            if upload.is_none() {
+                debug!("received ProjectChunk for project {} at offset {}", chunk.name.as_str(), chunk.offset);
+                if !self.projects.read().await.contains_key(&chunk.name) {
+                    debug!("project {} does not exist", chunk.name.as_str());
+                    return response!(UpdateResponse {
+                        error: Some(format!("Project '{}' does not exist", chunk.name.as_str())),
+                        project: chunk.name,
+                        hash: chunk.hash,
+                        success: false,
+                    });
+                }
+                if let Err(e) = self.check_idle(&chunk.name).await {
+                    debug!("could not accept upload for project {}: {}", chunk.name.as_str(), e.as_str());
+                    return response!(UpdateResponse {
+                        project: chunk.name,
+                        hash: chunk.hash,
+                        success: false,
+                        error: Some(e),
+                    });
+                }
+                let zip_upload = ZipUpload::open(&chunk.name, &chunk.hash, chunk.offset, &self.zip_cache_dir)
+                    .await
+                    .map_err(|e| Status::failed_precondition(format!("Could not continue upload: {}", e)))?;
+                // Remember upload info without the chunk data
+                let header = ProjectChunk {
+                    name: chunk.name.clone(),
+                    hash: chunk.hash.clone(),
+                    size: chunk.size,
+                    offset: chunk.offset,
+                    data: Vec::new(),
+                };
+                upload = Some((zip_upload, header));
+            }
+            let (zip_upload, first) = upload.as_mut().unwrap();
+            if chunk.name != first.name || chunk.hash != first.hash || chunk.offset != zip_upload.get_received() {
+                return Err(Status::invalid_argument(format!("Unexpected chunk for {}:{} at offset {}", chunk.name.as_str(), chunk.hash.as_str(), chunk.offset)));
+            }
+            zip_upload.append(&chunk.data)
+                .await
+                .map_err(|e| {
+                    error!("error occurred while trying to write chunk to file: {}", e);
+                    Status::aborted(format!("Error occurred while trying to write chunk to file: {}", e))
+                })?;
+        }
+        let (zip_upload, update) = upload
+            .ok_or_else(|| Status::invalid_argument("Upload did not contain any chunks"))?;
+
+        // Keep partial upload around to be resumed later
+        if zip_upload.get_received() < update.size {
+            debug!("upload for project {} incomplete: {}/{} bytes", update.name.as_str(), zip_upload.get_received(), update.size);
+            return response!(UpdateResponse {
+                error: Some(format!("Upload incomplete, received {}/{} bytes", zip_upload.get_received(), update.size)),
+                project: update.name,
+                hash: update.hash,
+                success: false,
+            });
+        }
+        if zip_upload.get_received() > update.size {
+            let _ = zip_upload.discard().await;
+            return Err(Status::invalid_argument(format!("Upload exceeds announced size of {} bytes", update.size)));
+        }
+        let zipfile = zip_upload.finish()
+            .await
+            .map_err(|e| {
+                error!("error occurred while trying to complete upload: {}", e);
+                Status::aborted(format!("Error occurred while trying to complete upload: {}", e))
+            })?;
+        // Upload can not be resumed if content does not match its hash
+        if !zipfile.compare_hash(&update.hash) {
+            let error = format!("Hashsum mismatch '{}'!='{}'", zipfile.get_hash(), update.hash.as_str());
+            debug!("could not apply update to project {}: {}", update.name.as_str(), error.as_str());
+            let _ = zipfile.remove().await;
+            return response!(UpdateResponse {
+                project: update.name,
+                hash: update.hash,
+                success: false,
+                error: Some(error),
+            });
+        }
+
+        let mut p = self.projects.write().await;
+        let project = p.get_mut(&update.name)
+            .ok_or_else(|| Status::not_found(format!("Project '{}' does not exist", update.name.as_str())))?;
+        // A run may have started while the upload was in progress
+        if let Err(e) = self.check_idle(&update.name).await {
+            debug!("could not apply update to project {}: {}", update.name.as_str(), e.as_str());
+            let _ = zipfile.remove().await;
+            return response!(UpdateResponse {
+                project: update.name,
+                hash: update.hash,
+                success: false,
+                error: Some(e),
+            });
+        }
+        match project.apply_update(zipfile, update.hash.clone(), &self.base_dir).await {
+            Ok(_) => {
+                // Flush after update apply
+                self.flush_projects();
+                info!("applied update {} to project {}", update.hash.as_str(), update.name.as_str());
+                response!(UpdateResponse {
+                    project: update.name,
+                    hash: update.hash,
+                    success: true,
+                    error: None,
+                })
+            },
+            Err(e) => {
+                debug!("could not apply update to project {}: {}", update.name.as_str(), e.as_str());
+                response!(UpdateResponse {
+                    project: update.name,
+                    hash: update.hash,
+                    success: false,
+                    error: Some(e)
+                })
+            },
+        }
+    }
+
+    async fn get_upload_status(
+        &self,
+        request: Request<UploadIdentifier>
+    ) -> Result<Response<UploadStatus>,Status> {
+        let upload = request.into_inner();
+        let received = ZipUpload::received(&upload.name, &upload.hash, &self.zip_cache_dir).await;
+        debug!("upload status for {}:{} -> {} bytes", upload.name.as_str(), upload.hash.as_str(), received);
+        response!(UploadStatus { received })
+    }
+
     async fn increment_project(
         &self,
-        _request: Request<ProjectIncrement>
+        request: Request<ProjectIncrement>
     ) ->Result<Response<UpdateResponse>,Status> {
-        // TODO: Add incremental update procedure
-        error!("client tried to apply ProjectIncrement, which is unimplemented!");
-        Err(Status::unimplemented("Not yet implemented!"))
+        let increment = request.into_inner();
+        debug!("received ProjectIncrement for project {}", increment.name.as_str());
+        let mut p = self.projects.write().await;
+        match p.get_mut(&increment.name) {
+            Some(project) => {
+                if let Err(e) = self.check_idle(&increment.name).await {
+                    debug!("could not apply increment to project {}: {}", increment.name.as_str(), e.as_str());
+                    return response!(UpdateResponse {
+                        project: increment.name,
+                        hash: increment.newhash,
+                        success: false,
+                        error: Some(e),
+                    });
+                }
+                debug!("preparing increment {} -> {} for project {}", increment.prevhash.as_str(), increment.newhash.as_str(), increment.name.as_str());
+                // Store content to local file
+                let zipfile = ZipFile::from_contents(increment.blob, &self.zip_cache_dir)
+                    .await
+                    .map_err(|e| {
+                        error!("error occurred while trying to write blob to file: {}", e);
+                        Status::aborted(format!("Error occurred while trying to write blob to file: {}", e))
+                    })?;
+                match project.apply_increment(zipfile, increment.prevhash, increment.newhash.clone(), increment.deleted, &self.base_dir)
+                .await {
+                    Ok(_) => {
+                        // Flush after increment apply
+                        self.flush_projects();
+                        info!("applied increment {} to project {}", increment.newhash.as_str(), increment.name.as_str());
+                        response!(UpdateResponse {
+                            project: increment.name,
+                            hash: increment.newhash,
+                            success: true,
+                            error: None,
+                        })
+                    },
+                    Err(e) => {
+                        debug!("could not apply increment to project {}: {}", increment.name.as_str(), e.as_str());
+                        response!(UpdateResponse {
+                            project: increment.name,
+                            hash: increment.newhash,
+                            success: false,
+                            error: Some(e)
+                        })
+                    },
+                }
+            },
+            // no project with this name
+            None => {
+                debug!("project {} does not exist", increment.name.as_str());
+                response!(UpdateResponse {
+                    error: Some(format!("Project '{}' does not exist", increment.name.as_str())),
+                    project: increment.name,
+                    hash: increment.newhash,
+                    success: false,
+                })
+            },
+        }
     }
 
     async fn run_tests(
         &self,
-        request: Request<ProjectIdentifier>
+        request: Request<RunRequest>
     ) -> Result<Response<TestResults>,Status> {
-        let project = request.into_inner().name;
-        debug!("received RunTest request for project {}", project.as_str());
-
-        // Generate pre-test timestamp
-        let timestamp = chrono::Utc::now()
-                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
-
-        // Get project info
-        let p = self.projects.read().await;
-        let test_project = p.get(&project)
-            .ok_or({
-                debug!("project {} does not exist", project.as_str());
-                Status::invalid_argument(format!("Project '{}' does not exist!", project.as_str()))
-            })?;
+        let request = request.into_inner();
+        debug!("received RunTest request for project {}", request.name.as_str());
 
-        // Run all configured tests for project
-        let results = test_project.execute_all_tests(&self.base_dir)
-            .await
-            .map(|v| v.into_iter()
-                .map(|k| TestResult::from(k))
-                .collect()
-            )
-            .map_err(|e| {
-                error!("error occured while running test: {}", e);
-                Status::aborted(format!("Error occurred while running test: {}", e))
-            })?;
+        // Run selected tests for project
+        let (_, handle) = self.spawn_run(request, None).await?;
+        let results = handle.await
+            .map_err(|e| Status::internal(format!("Test run failed: {}", e)))?
+            .map_err(|e| Status::aborted(format!("Error occurred while running test: {}", e)))?;
 
         // Return test results
-        let (name, hash) = test_project.get_tuple();
-        info!("Ran tests for project {}:{}", name.as_str(), hash.as_str());
-        response!(TestResults {
-            name,
-            hash,
-            timestamp,
-            results,
-        })
+        info!("Ran tests for project {}:{}", results.name.as_str(), results.hash.as_str());
+        response!(results)
+    }
+
+    type StreamTestsStream = ReceiverStream<Result<TestEvent, Status>>;
+
+    async fn stream_tests(
+        &self,
+        request: Request<RunRequest>
+    ) -> Result<Response<Self::StreamTestsStream>,Status> {
+        let request = request.into_inner();
+        debug!("received StreamTests request for project {}", request.name.as_str());
+
+        let (events, mut event_rx) = mpsc::channel(EVENT_BUFFER);
+        let (_, handle) = self.spawn_run(request, Some(events)).await?;
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        tokio::spawn(async move {
+            // Forward test events to the client while tests are running,
+            // output is dropped by the run if the client falls behind and
+            // events stop altogether if it stalls, results still follow
+            while let Some(event) = event_rx.recv().await {
+                let _ = tx.send(Ok(event)).await;
+            }
+            // Finish stream with complete results
+            let last = match handle.await {
+                Ok(Ok(results)) => {
+                    info!("Ran tests for project {}:{}", results.name.as_str(), results.hash.as_str());
+                    Ok(TestEvent { event: Some(Event::Results(results)) })
+                },
+                Ok(Err(e)) => Err(Status::aborted(format!("Error occurred while running test: {}", e))),
+                Err(e) => Err(Status::internal(format!("Test run failed: {}", e))),
+            };
+            let _ = tx.send(last).await;
+        });
+        response!(ReceiverStream::new(rx))
+    }
+
+    async fn start_run(
+        &self,
+        request: Request<RunRequest>
+    ) -> Result<Response<RunIdentifier>,Status> {
+        let request = request.into_inner();
+        debug!("received StartRun request for project {}", request.name.as_str());
+        let (run_id, _) = self.spawn_run(request, None).await?;
+        response!(RunIdentifier { run_id })
+    }
+
+    async fn get_run(
+        &self,
+        request: Request<RunIdentifier>
+    ) -> Result<Response<RunInfo>,Status> {
+        let run_id = request.into_inner().run_id;
+        debug!("received GetRun request for run {}", run_id.as_str());
+        if let Some(info) = self.runs.get(&run_id).await {
+            return response!(info);
+        }
+        // Run might have been forgotten by the registry, but still be stored
+        match self.history.get(&run_id).await {
+            Ok(Some(info)) => response!(info),
+            Ok(None) => Err(Status::not_found(format!("Run '{}' does not exist", run_id.as_str()))),
+            Err(e) => Err(Status::internal(format!("Could not read run history: {}", e))),
+        }
+    }
+
+    async fn list_runs(
+        &self,
+        request: Request<ListRunsRequest>
+    ) -> Result<Response<RunList>,Status> {
+        let project = request.into_inner().project;
+        debug!("received ListRuns request");
+        let runs = self.runs.list(project.as_deref()).await;
+        response!(RunList { runs })
+    }
+
+    async fn get_run_history(
+        &self,
+        request: Request<RunHistoryRequest>
+    ) -> Result<Response<RunHistory>,Status> {
+        let request = request.into_inner();
+        debug!("received GetRunHistory request for project {}", request.project.as_str());
+        if !self.projects.read().await.contains_key(&request.project) {
+            return Err(Status::not_found(format!("Project '{}' does not exist", request.project.as_str())));
+        }
+        let limit = request.limit
+            .map(|l| l as usize)
+            .unwrap_or(history::DEFAULT_PAGE_SIZE)
+            .min(history::MAX_PAGE_SIZE);
+        let (runs, total) = self.history.page(&request.project, request.offset as usize, limit, request.with_output)
+            .await
+            .map_err(|e| Status::internal(format!("Could not read run history: {}", e)))?;
+        response!(RunHistory { runs, total: total as u32 })
+    }
+
+    type GetTestOutputStream = ReceiverStream<Result<OutputChunk, Status>>;
+
+    async fn get_test_output(
+        &self,
+        request: Request<OutputRequest>
+    ) -> Result<Response<Self::GetTestOutputStream>,Status> {
+        let request = request.into_inner();
+        debug!("received GetTestOutput request for run {}", request.run_id.as_str());
+        let info = self.find_run(&request.run_id).await?;
+        let dir = self.history.output_dir(&info.project).join(&info.run_id);
+        let path = output::spool_file(&dir, request.phase(), request.index as usize, request.iteration, request.attempt, request.stream());
+        let mut file = tokio::fs::File::open(&path)
+            .await
+            .map_err(|_| Status::not_found(format!("No output stored for command {} of run '{}'", request.index, request.run_id.as_str())))?;
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; OUTPUT_CHUNK_SIZE];
+            loop {
+                let chunk = match file.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => Ok(OutputChunk {
+                        index: request.index,
+                        stream: request.stream,
+                        data: buf[..n].to_vec(),
+                        phase: request.phase,
+                    }),
+                    Err(e) => Err(Status::internal(format!("Could not read output: {}", e))),
+                };
+                let failed = chunk.is_err();
+                if tx.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        response!(ReceiverStream::new(rx))
+    }
+
+    type DownloadArtifactsStream = ReceiverStream<Result<ArtifactChunk, Status>>;
+
+    async fn download_artifacts(
+        &self,
+        request: Request<ArtifactRequest>
+    ) -> Result<Response<Self::DownloadArtifactsStream>,Status> {
+        let request = request.into_inner();
+        debug!("received DownloadArtifacts request for run {}", request.run_id.as_str());
+        let info = self.find_run(&request.run_id).await?;
+        let collected = info.results
+            .map(|r| r.artifacts)
+            .unwrap_or_default();
+        let paths = if request.paths.is_empty() {
+            collected
+        } else {
+            if let Some(path) = request.paths.iter().find(|p| !collected.contains(p)) {
+                return Err(Status::not_found(format!("No artifact '{}' collected for run '{}'", path.as_str(), request.run_id.as_str())));
+            }
+            request.paths
+        };
+        let dir = self.history.output_dir(&info.project)
+            .join(&info.run_id)
+            .join(artifacts::ARTIFACT_DIR);
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; OUTPUT_CHUNK_SIZE];
+            for path in paths {
+                let file = match artifacts::artifact_file(&dir, &path) {
+                    Some(file) => tokio::fs::File::open(file).await,
+                    None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid path")),
+                };
+                let mut file = match file {
+                    Ok(file) => file,
+                    Err(e) => {
+                        let _ = tx.send(Err(Status::not_found(format!("Could not open artifact '{}': {}", path.as_str(), e)))).await;
+                        return;
+                    },
+                };
+                let mut first = true;
+                loop {
+                    let chunk = match file.read(&mut buf).await {
+                        // Empty files are still sent as a single chunk
+                        Ok(0) if !first => break,
+                        Ok(n) => Ok(ArtifactChunk {
+                            path: path.clone(),
+                            data: buf[..n].to_vec(),
+                            first,
+                        }),
+                        Err(e) => Err(Status::internal(format!("Could not read artifact '{}': {}", path.as_str(), e))),
+                    };
+                    first = false;
+                    let failed = chunk.is_err();
+                    if tx.send(chunk).await.is_err() || failed {
+                        return;
+                    }
+                }
+            }
+        });
+        response!(ReceiverStream::new(rx))
+    }
+
+    async fn cancel_run(
+        &self,
+        request: Request<RunIdentifier>
+    ) -> Result<Response<CancelResponse>,Status> {
+        let run_id = request.into_inner().run_id;
+        debug!("received CancelRun request for run {}", run_id.as_str());
+        match self.runs.cancel(&run_id).await {
+            Ok(_) => response!(CancelResponse { success: true, error: None }),
+            Err(e) => response!(CancelResponse { success: false, error: Some(e) }),
+        }
+    }
+
+    async fn list_projects(
+        &self,
+        _request: Request<ListProjectsRequest>
+    ) -> Result<Response<ProjectList>,Status> {
+        debug!("received ListProjects request");
+        let mut projects: Vec<ProjectInfo> = self.projects.read().await
+            .values()
+            .map(TestProject::info)
+            .collect();
+        projects.sort_by(|a, b| a.project.as_ref().map(|p| &p.name).cmp(&b.project.as_ref().map(|p| &p.name)));
+        response!(ProjectList { projects })
+    }
+
+    async fn get_project(
+        &self,
+        request: Request<ProjectIdentifier>
+    ) -> Result<Response<ProjectInfo>,Status> {
+        let project = request.into_inner().name;
+        debug!("received GetProject request for project {}", project.as_str());
+        match self.projects.read().await.get(&project) {
+            Some(p) => response!(p.info()),
+            None => Err(Status::not_found(format!("Project '{}' does not exist", project.as_str()))),
+        }
     }
 }
 
@@ -289,8 +823,60 @@ impl log::Log for SimpleLogger {
     fn flush(&self) {}
 }
 
-static DEFAULT_REPO_DIR: &'static str = "/var/remote-test";
-static DEFAULT_ZIP_CACHE_DIR: &'static str = "/tmp/.remote-test_zip-cache.d";
+/// Reads capsule configuration from the environment
+///
+/// CAPSULE selects the default capsule (transparent, sandbox, kubernetes or
+/// container), the kubernetes capsule is available to projects if
+/// KUBE_NAMESPACE is set, the container capsule if CONTAINER_RUNTIME is set
+/// (docker, podman or path of a compatible executable).
+/// Pods are started with KUBECTL using KUBE_CONTEXT and KUBE_TOKEN, if set.
+/// Tests of transparent capsules get cgroups in CGROUP_DIR, if set.
+fn capsule_config() -> CapsuleConfig {
+    let kubernetes = std::env::var("KUBE_NAMESPACE").ok().map(|namespace| {
+        let mut options = PodOptions::default();
+        if let Ok(image) = std::env::var("KUBE_IMAGE") {
+            options.set_image(image);
+        }
+        KubernetesCapsule::new(
+            std::env::var("KUBECTL").ok(),
+            std::env::var("KUBE_TOKEN").unwrap_or_default(),
+            namespace,
+            std::env::var("KUBE_CONTEXT").ok(),
+            options,
+        )
+    });
+    let container = std::env::var("CONTAINER_RUNTIME").ok().map(|runtime| {
+        let mut options = ContainerOptions::default();
+        if let Ok(image) = std::env::var("CONTAINER_IMAGE") {
+            options.set_image(image);
+        }
+        if let Ok(network) = std::env::var("CONTAINER_NETWORK") {
+            options.set_network(network);
+        }
+        ContainerCapsule::new(Some(runtime), options)
+    });
+    let default = match std::env::var("CAPSULE").as_deref() {
+        Ok("kubernetes") if kubernetes.is_some() => CapsuleChoice::Kubernetes(PodOptions::default()),
+        Ok("kubernetes") => panic!("CAPSULE=kubernetes requires KUBE_NAMESPACE to be set"),
+        Ok("container") if container.is_some() => CapsuleChoice::Container(ContainerOptions::default()),
+        Ok("container") => panic!("CAPSULE=container requires CONTAINER_RUNTIME to be set"),
+        Ok("sandbox") => CapsuleChoice::Sandbox(SandboxOptions::default()),
+        Ok("transparent") | Err(_) => CapsuleChoice::Transparent,
+        Ok(other) => panic!("Unknown capsule '{}'", other),
+    };
+    let cgroup = std::env::var("CGROUP_DIR").ok().map(PathBuf::from);
+    CapsuleConfig { default, kubernetes, container, cgroup }
+}
+
+static DEFAULT_REPO_DIR: &str = "/var/remote-test";
+static DEFAULT_ZIP_CACHE_DIR: &str = "/tmp/.remote-test_zip-cache.d";
+static DEFAULT_HISTORY_DIR: &str = "/var/remote-test-history";
+/// Number of ended runs kept per project by default
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+/// KB of output kept from start and end of each stream by default
+const DEFAULT_OUTPUT_LIMIT_KB: u64 = 64;
+/// Bytes of spooled output sent per message
+const OUTPUT_CHUNK_SIZE: usize = 64 * 1024;
 
 #[tokio::main]
 async fn main() {
@@ -300,6 +886,20 @@ async fn main() {
     let zip_cache_dir = prepare_directory(std::env::var("ZIP_CACHE_DIR").unwrap_or(DEFAULT_ZIP_CACHE_DIR.to_string()).as_str())
         .await
         .expect("Could not prepare ZIP_CACHE_DIR");
+    let history_dir = prepare_directory(std::env::var("HISTORY_DIR").unwrap_or(DEFAULT_HISTORY_DIR.to_string()).as_str())
+        .await
+        .expect("Could not prepare HISTORY_DIR");
+    let history_limit = std::env::var("HISTORY_LIMIT")
+        .map(|s| s.parse::<usize>().expect("Could not parse history limit"))
+        .unwrap_or(DEFAULT_HISTORY_LIMIT);
+    // Run as many tests at the same time as there are cores by default
+    let max_parallel = std::env::var("MAX_PARALLEL")
+        .map(|s| s.parse::<usize>().expect("Could not parse max parallel tests"))
+        .unwrap_or_else(|_| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+    // Output is kept completely if set to 0
+    let output_limit_kb = std::env::var("OUTPUT_LIMIT_KB")
+        .map(|s| s.parse::<u64>().expect("Could not parse output limit"))
+        .unwrap_or(DEFAULT_OUTPUT_LIMIT_KB);
     let projects = std::fs::File::open("projects.json")
         .ok()
         .map(|f| {
@@ -307,7 +907,7 @@ async fn main() {
             v
         });
 
-    let ctx = RemoteServerContext::new(repo_dir, zip_cache_dir);
+    let ctx = RemoteServerContext::new(repo_dir, zip_cache_dir, history::RunHistory::new(history_dir, history_limit), capsule_config(), max_parallel, output_limit_kb);
     if let Some(ps) = projects {
         let n = ps.len();
         ctx.add_projects(ps).await;
@@ -320,7 +920,7 @@ async fn main() {
     log::set_logger(&LOGGER).unwrap();
     log::set_max_level(log::LevelFilter::Debug);
 
-    let port = u16::from_str_radix(std::env::var("PORT").unwrap_or("19000".to_string()).as_str(), 10).expect("Could not parse port number");
+    let port = std::env::var("PORT").unwrap_or("19000".to_string()).parse::<u16>().expect("Could not parse port number");
     let host = if let Ok(host_env) = std::env::var("HOST") {
         let ip = IpAddr::from_str(host_env.as_str()).expect("Could not parse specified host");
         SocketAddr::from((ip, port))
@@ -1,10 +1,58 @@
-use std::{error::Error, io::Cursor, path::{Path, PathBuf}};
+use std::{error::Error, io::{Cursor, Read}, path::{Path, PathBuf}};
 
 use regex::Regex;
 use walkdir::WalkDir;
 use zip::ZipWriter;
 
-use crate::hash::hash;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::{hash::{hash, hash_file}, manifest::Manifest};
+
+/// Parses list of regex patterns, returning the first parse error if any
+pub fn parse_patterns(patterns: Vec<String>) -> Result<Vec<Regex>, Box<dyn Error>> {
+    let patterns: Vec<Result<Regex, regex::Error>> = patterns.into_iter()
+        .map(|e| Regex::new(e.as_str()))
+        .collect();
+    // Extract possible regex parse errors
+    let err = patterns.iter()
+        .find_map(|e| match e {
+            Ok(_) => None,
+            Err(e) => Some(e.to_owned()),
+        });
+    if let Some(err) = err {
+        return Err(Box::new(err));
+    }
+    // Use parsed Regex patterns
+    Ok(patterns.into_iter().filter_map(|e| e.ok()).collect())
+}
+
+/// Lists all files in dir whose paths do not match any of the exclude patterns
+pub fn list_files(dir: impl AsRef<Path>, exclude: &[Regex]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    let walker = WalkDir::new(dir).min_depth(1);
+    for entry in walker {
+        let dir_entry = entry?;
+
+        // Filter out excluded paths
+        let path = if dir_entry.path_is_symlink() {
+            std::fs::read_link(dir_entry.path())?
+        } else {
+            dir_entry.path().to_path_buf()
+        };
+        let path_str = path.to_string_lossy();
+        let is_excluded = exclude.iter().any(|p| p.is_match(path_str.as_ref()));
+        // Filter out paths matching excluded regex
+        if is_excluded {
+            // Skip this entry
+            continue;
+        }
+
+        if dir_entry.file_type().is_file() {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
 
 pub struct ZipFile {
     hash: String,
@@ -20,10 +68,10 @@ impl From<(String, PathBuf)> for ZipFile {
 
 impl ZipFile {
     /// Create local zip file from sent content
-    pub async fn from_contents(content: Vec<u8>, base_dir: &PathBuf) -> Result<ZipFile, Box<dyn Error>> {
+    pub async fn from_contents(content: Vec<u8>, base_dir: &Path) -> Result<ZipFile, Box<dyn Error>> {
         // Generate path for local file
         let path = {
-            let mut p = base_dir.clone();
+            let mut p = base_dir.to_path_buf();
             let timestamp = chrono::Utc::now()
                 .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
             let filename = format!("zip-cached-{}", timestamp.as_str());
@@ -33,21 +81,46 @@ impl ZipFile {
         // Calculate hash from content
         let hash = hash(&content).await;
         // Write content to local path
-        let _ = tokio::fs::write(path.as_path(), content).await?;
+        tokio::fs::write(path.as_path(), content).await?;
         // Create zipfile struct
         Ok(ZipFile::from((hash, path)))
     }
 
     /// Extract this zipfile into the target folder
-    pub async fn extract_into(self, dir: &PathBuf) -> Result<(), Box<dyn Error>> {
+    pub async fn extract_into(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
         // Read zipfile content from file
-        let zipfile = tokio::fs::File::open(self.path).await?;
+        let zipfile = tokio::fs::File::open(self.path.as_path()).await?;
         let mut zip = zip::ZipArchive::new(zipfile.try_into_std().unwrap())?;
         // Extract into target dir
-        let _ = zip.extract(dir)?;
+        zip.extract(dir)?;
         Ok(())
     }
 
+    /// Hashes all files contained in this zipfile
+    pub async fn manifest(&self) -> Result<Manifest, Box<dyn Error>> {
+        let zipfile = tokio::fs::File::open(self.path.as_path()).await?;
+        let mut zip = zip::ZipArchive::new(zipfile.try_into_std().unwrap())?;
+        let mut manifest = Manifest::default();
+        for i in 0..zip.len() {
+            let (name, content) = {
+                let mut file = zip.by_index(i)?;
+                if file.is_dir() {
+                    continue;
+                }
+                let mut content = Vec::with_capacity(file.size() as usize);
+                file.read_to_end(&mut content)?;
+                (file.name().to_string(), content)
+            };
+            manifest.insert(name, hash(&content).await);
+        }
+        Ok(manifest)
+    }
+
+    /// Deletes the local zip file
+    pub async fn remove(self) -> std::io::Result<()> {
+        tokio::fs::remove_file(self.path).await
+    }
+
     pub fn compare_hash(&self, other: &String) -> bool {
         self.hash == *other
     }
@@ -57,71 +130,124 @@ impl ZipFile {
     }
 }
 
+/// Zipfile received in chunks
+///
+/// Partial uploads are kept in the cache dir under a name derived from
+/// project name and hash, so an interrupted upload can be resumed later
+pub struct ZipUpload {
+    path: PathBuf,
+    file: tokio::fs::File,
+    received: u64,
+}
+
+impl ZipUpload {
+    fn upload_path(name: &str, hash: &str, base_dir: &Path) -> PathBuf {
+        let sanitize = |s: &str| s.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        base_dir.join(format!("zip-upload-{}-{}", sanitize(name), sanitize(hash)))
+    }
+
+    /// Returns number of bytes already stored for this upload
+    pub async fn received(name: &str, hash: &str, base_dir: &Path) -> u64 {
+        tokio::fs::metadata(Self::upload_path(name, hash, base_dir))
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+
+    /// Opens upload to continue writing at offset
+    /// offset may not lie beyond the data already received
+    pub async fn open(name: &str, hash: &str, offset: u64, base_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let path = Self::upload_path(name, hash, base_dir);
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.as_path())
+            .await?;
+        let len = file.metadata().await?.len();
+        if offset > len {
+            return Err(format!("Upload offset {} is beyond received data ({} bytes)", offset, len).into());
+        }
+        // Drop anything received after offset, it is sent again
+        file.set_len(offset).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        Ok(ZipUpload { path, file, received: offset })
+    }
+
+    /// Writes data at the current end of the upload
+    pub async fn append(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.file.write_all(data).await?;
+        // Make sure data is stored when the connection drops afterwards
+        self.file.flush().await?;
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn get_received(&self) -> u64 {
+        self.received
+    }
+
+    /// Completes upload, hashing the received content
+    pub async fn finish(self) -> Result<ZipFile, Box<dyn Error>> {
+        self.file.sync_all().await?;
+        let hash = hash_file(self.path.as_path()).await?;
+        Ok(ZipFile::from((hash, self.path)))
+    }
+
+    /// Removes received data
+    pub async fn discard(self) -> Result<(), Box<dyn Error>> {
+        tokio::fs::remove_file(self.path).await?;
+        Ok(())
+    }
+}
+
 pub struct ZipBlob {
     zip: ZipWriter<Cursor<Vec<u8>>>,
     options: zip::write::FileOptions,
-    exclude: Vec<Regex>
+    exclude: Vec<Regex>,
+    manifest: Manifest,
 }
 
 impl ZipBlob {
     pub fn new(exclude: Vec<String>) -> Result<Self, Box<dyn Error>> {
-        let exclude: Vec<Result<Regex, regex::Error>> = exclude.into_iter()
-            .map(|e| Regex::new(e.as_str()))
-            .collect();
-        // Extract possible regex parse errors
-        let err = exclude.iter()
-            .find_map(|e| match e {
-                Ok(_) => None,
-                Err(e) => Some(e.to_owned()),
-            });
-        if let Some(err) = err {
-            return Err(Box::new(err));
-        }
-        // Use parsed Regex patterns
-        let exclude = exclude.into_iter().filter_map(|e| e.ok()).collect();
+        let exclude = parse_patterns(exclude)?;
 
         // Create zip writer
         let zip = ZipWriter::new(Cursor::new(Vec::new()));
         let options = zip::write::FileOptions::default();
 
         Ok(ZipBlob {
-            zip, options, exclude
+            zip, options, exclude, manifest: Manifest::default(),
         })
     }
 
     pub async fn add_dir(&mut self, dir: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
-        use std::io::Write;
         println!("\n*** Compressing project directory");
-        let walker = WalkDir::new(dir).min_depth(1);
-        for entry in walker {
-            let dir_entry = entry?;
-
-            // Filter out excluded paths
-            let path;
-            if dir_entry.path_is_symlink() {
-                path = std::fs::read_link(dir_entry.path())?;
-            } else {
-                path = dir_entry.path().to_path_buf();
-            };
-            let path_str = path.to_string_lossy();
-            let is_excluded = self.exclude.iter().any(|p| p.is_match(path_str.as_ref()));
-            // Filter out paths matching excluded regex
-            if is_excluded {
-                // Skip this entry
-                continue;
-            }
-
-            // Feed to zip writer if file
-            if dir_entry.file_type().is_file() {
-                println!("\t{}", path_str);
-                self.zip.start_file(path_str, self.options.clone())?;
-                let content = tokio::fs::read(path.as_path()).await?;
-                self.zip.write(&content)?;
-            }
+        for path in list_files(dir, &self.exclude)? {
+            self.add_file(path.as_path()).await?;
         }
         Ok(())
     }
 
+    /// Feeds a single file to the zip writer
+    pub async fn add_file(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        use std::io::Write;
+        let path_str = path.to_string_lossy().to_string();
+        println!("\t{}", path_str);
+        self.zip.start_file(path_str.as_str(), self.options)?;
+        let content = tokio::fs::read(path).await?;
+        self.zip.write_all(&content)?;
+        self.manifest.insert(path_str, hash(&content).await);
+        Ok(())
+    }
+
+    /// Manifest of all files added so far
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
     /// Finalizes zip process and returns a tuple of the base64-encoded hash
     /// and the actual data blob
     pub async fn finish(mut self) -> Result<(String, Vec<u8>), Box<dyn Error>> {
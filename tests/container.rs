@@ -0,0 +1,189 @@
+use std::{path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use remote_test::capsule::{Capsule, ContainerCapsule, ContainerOptions};
+use remote_test::pb::TestStatus;
+use remote_test::project::{RunControl, TestEnv};
+
+/// Directory tests/fake-docker is linked into, see there for its contents
+struct FakeDocker {
+    dir: PathBuf,
+}
+
+impl FakeDocker {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rt-fake-docker-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("project")).unwrap();
+        std::fs::write(dir.join("project").join("data.txt"), "data").unwrap();
+        let script = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fake-docker");
+        std::os::unix::fs::symlink(script, dir.join("docker")).unwrap();
+        FakeDocker { dir }
+    }
+
+    fn capsule(&self, copy: bool) -> ContainerCapsule {
+        let mut options = ContainerOptions::default();
+        options.set_image(String::from("alpine"));
+        options.set_copy(copy);
+        ContainerCapsule::new(Some(self.dir.join("docker").to_string_lossy().to_string()), options)
+    }
+
+    fn project(&self) -> PathBuf {
+        self.dir.join("project")
+    }
+
+    fn fail(&self, cmd: &str) {
+        std::fs::write(self.dir.join(format!("fail-{}", cmd)), "").unwrap();
+    }
+
+    fn read(&self, name: &str) -> String {
+        std::fs::read_to_string(self.dir.join(name)).unwrap_or_default()
+    }
+
+    fn calls(&self) -> Vec<String> {
+        self.read("calls").lines().map(String::from).collect()
+    }
+
+    fn running(&self) -> bool {
+        self.dir.join("running").exists()
+    }
+}
+
+impl Drop for FakeDocker {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn command(cmd: &str) -> Vec<String> {
+    shell_words::split(cmd).unwrap()
+}
+
+/// Waits for process pid to be gone
+fn exited(pid: i32) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        if unsafe { libc::kill(pid, 0) } != 0 {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+#[tokio::test]
+async fn starts_container_with_project_mounted() {
+    let docker = FakeDocker::new("volume");
+    let mut capsule = docker.capsule(false);
+    let mut options = ContainerOptions::default();
+    options.set_network(String::from("tests"));
+    capsule.encapsulate(String::from("volume"), &docker.project(), options).await.unwrap();
+    let project = std::fs::canonicalize(docker.project()).unwrap();
+    assert_eq!(docker.calls(), vec![format!(
+        "run --detach --name=rtc-capsule-volume --workdir=/repo --network=tests --volume={}:/repo alpine sleep infinity",
+        project.display(),
+    )]);
+    assert!(docker.running());
+    assert_eq!(docker.read("volume").trim(), project.to_string_lossy());
+    // Tests ran in the mounted directory, nothing is copied back
+    assert_eq!(capsule.workspace(&docker.dir.join("scratch")).await.unwrap(), project);
+    assert_eq!(docker.calls().len(), 1);
+    capsule.discard().await.unwrap();
+    assert_eq!(docker.calls().last().unwrap(), "rm --force rtc-capsule-volume");
+    assert!(!docker.running());
+}
+
+#[tokio::test]
+async fn copies_project_in_copy_mode() {
+    let docker = FakeDocker::new("copy");
+    let mut capsule = docker.capsule(true);
+    capsule.encapsulate(String::from("copy"), &docker.project(), ContainerOptions::default()).await.unwrap();
+    let calls = docker.calls();
+    assert!(calls.iter().all(|c| !c.contains("--volume")), "{:?}", calls);
+    assert!(calls[1].ends_with(" rtc-capsule-copy:/repo"), "{}", calls[1]);
+    assert_eq!(docker.read("repo/data.txt"), "data");
+    let output = capsule.run_test(0, &command("sh -c 'echo result > result.txt'"), &TestEnv::default(), None, &RunControl::default()).await.unwrap();
+    assert_eq!(output.status, TestStatus::Passed);
+    assert!(!docker.project().join("result.txt").exists());
+    let scratch = docker.dir.join("scratch");
+    assert_eq!(capsule.workspace(&scratch).await.unwrap(), scratch);
+    assert_eq!(std::fs::read_to_string(scratch.join("result.txt")).unwrap(), "result\n");
+    capsule.discard().await.unwrap();
+    assert!(!docker.running());
+}
+
+#[tokio::test]
+async fn removes_container_if_copy_fails() {
+    let docker = FakeDocker::new("copy-fails");
+    docker.fail("cp");
+    let mut capsule = docker.capsule(true);
+    let err = capsule.encapsulate(String::from("copy-fails"), &docker.project(), ContainerOptions::default()).await.unwrap_err();
+    assert!(err.to_string().contains("cp failed"), "{}", err);
+    assert_eq!(docker.calls().last().unwrap(), "rm --force rtc-capsule-copy-fails");
+    assert!(!docker.running());
+}
+
+#[tokio::test]
+async fn requires_image() {
+    let docker = FakeDocker::new("no-image");
+    let mut capsule = ContainerCapsule::new(Some(docker.dir.join("docker").to_string_lossy().to_string()), ContainerOptions::default());
+    let err = capsule.encapsulate(String::from("no-image"), &docker.project(), ContainerOptions::default()).await.unwrap_err();
+    assert_eq!(err.to_string(), "No image configured for container capsule");
+    assert!(docker.calls().is_empty());
+}
+
+#[tokio::test]
+async fn runs_tests_in_container() {
+    let docker = FakeDocker::new("exec");
+    let mut capsule = docker.capsule(false);
+    capsule.encapsulate(String::from("exec"), &docker.project(), ContainerOptions::default()).await.unwrap();
+    let mut env = TestEnv::default();
+    env.vars.insert(String::from("GREETING"), String::from("hello"));
+    let control = RunControl::default();
+    let output = capsule.run_test(0, &command("sh -c 'cat data.txt; echo \" $GREETING\"'"), &env, None, &control).await.unwrap();
+    assert_eq!(output.status, TestStatus::Passed);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "data hello\n");
+    let output = capsule.run_test(1, &command("sh -c 'echo failed >&2; exit 3'"), &env, None, &control).await.unwrap();
+    assert_eq!(output.status, TestStatus::Failed);
+    assert_eq!(output.exit_code, Some(3));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "failed\n");
+    // Values are passed on stdin, not as arguments
+    assert!(docker.calls().iter().all(|c| !c.contains("hello")));
+    capsule.discard().await.unwrap();
+}
+
+#[tokio::test]
+async fn clears_environment_in_container() {
+    let docker = FakeDocker::new("clear-env");
+    let mut capsule = docker.capsule(false);
+    capsule.encapsulate(String::from("clear-env"), &docker.project(), ContainerOptions::default()).await.unwrap();
+    let mut env = TestEnv { clear: true, ..TestEnv::default() };
+    env.vars.insert(String::from("GREETING"), String::from("it's \"quoted\"\n$HOME"));
+    let output = capsule.run_test(0, &command("sh -c 'echo \"$HOME|$GREETING\"'"), &env, None, &RunControl::default()).await.unwrap();
+    assert_eq!(output.status, TestStatus::Passed);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "|it's \"quoted\"\n$HOME\n");
+    capsule.discard().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_empty_command() {
+    let docker = FakeDocker::new("empty");
+    let mut capsule = docker.capsule(false);
+    capsule.encapsulate(String::from("empty"), &docker.project(), ContainerOptions::default()).await.unwrap();
+    let err = capsule.run_test(0, &[], &TestEnv::default(), None, &RunControl::default()).await.unwrap_err();
+    assert_eq!(err.to_string(), "Test command is empty");
+    capsule.discard().await.unwrap();
+}
+
+#[tokio::test]
+async fn kills_process_group_of_timed_out_tests() {
+    let docker = FakeDocker::new("timeout");
+    let mut capsule = docker.capsule(false);
+    capsule.encapsulate(String::from("timeout"), &docker.project(), ContainerOptions::default()).await.unwrap();
+    // Background processes started by the test are killed along with it
+    let output = capsule.run_test(0, &command("sh -c 'sleep 10 & echo $! > child.pid; wait'"), &TestEnv::default(), Some(Duration::from_millis(500)), &RunControl::default()).await.unwrap();
+    assert_eq!(output.status, TestStatus::TimedOut);
+    assert!(docker.calls().iter().any(|c| c.starts_with("exec rtc-capsule-timeout sh -c exec kill -KILL -- -")));
+    let child: i32 = docker.read("project/child.pid").trim().parse().unwrap();
+    assert!(exited(child), "process {} started by the test is still running", child);
+    capsule.discard().await.unwrap();
+}
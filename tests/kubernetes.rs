@@ -0,0 +1,209 @@
+use std::{path::{Path, PathBuf}, time::{Duration, Instant}};
+
+use remote_test::capsule::{Capsule, KubernetesCapsule, PodOptions};
+use remote_test::pb::TestStatus;
+use remote_test::project::{RunControl, TestEnv};
+
+/// Directory tests/fake-kubectl is linked into, see there for its contents
+struct FakeKubectl {
+    dir: PathBuf,
+}
+
+impl FakeKubectl {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rt-fake-kubectl-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("project")).unwrap();
+        std::fs::write(dir.join("project").join("data.txt"), "data").unwrap();
+        let script = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fake-kubectl");
+        std::os::unix::fs::symlink(script, dir.join("kubectl")).unwrap();
+        FakeKubectl { dir }
+    }
+
+    fn capsule(&self, token: &str) -> KubernetesCapsule {
+        let mut options = PodOptions::default();
+        options.set_image(String::from("alpine"));
+        KubernetesCapsule::new(
+            Some(self.dir.join("kubectl").to_string_lossy().to_string()),
+            token.to_string(),
+            String::from("tests"),
+            Some(String::from("cluster")),
+            options,
+        )
+    }
+
+    fn project(&self) -> PathBuf {
+        self.dir.join("project")
+    }
+
+    fn fail(&self, cmd: &str) {
+        std::fs::write(self.dir.join(format!("fail-{}", cmd)), "").unwrap();
+    }
+
+    fn read(&self, name: &str) -> String {
+        std::fs::read_to_string(self.dir.join(name)).unwrap_or_default()
+    }
+
+    fn calls(&self) -> Vec<String> {
+        self.read("calls").lines().map(String::from).collect()
+    }
+}
+
+impl Drop for FakeKubectl {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn command(cmd: &str) -> Vec<String> {
+    shell_words::split(cmd).unwrap()
+}
+
+/// Waits for process pid to be gone
+fn exited(pid: i32) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        if unsafe { libc::kill(pid, 0) } != 0 {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+#[tokio::test]
+async fn passes_cluster_options_without_token_in_args() {
+    let kubectl = FakeKubectl::new("options");
+    let mut capsule = kubectl.capsule("secret-token");
+    capsule.encapsulate(String::from("options"), &kubectl.project(), PodOptions::default()).await.unwrap();
+    let calls = kubectl.calls();
+    assert!(!calls.is_empty());
+    for call in calls.iter() {
+        assert!(call.starts_with("--namespace=tests --context=cluster --user=remote-test "), "{}", call);
+        assert!(!call.contains("secret-token"), "{}", call);
+    }
+    assert!(kubectl.read("kubeconfig").contains("secret-token"));
+    let kubeconfig = PathBuf::from(kubectl.read("kubeconfig-path").trim());
+    assert!(kubeconfig.exists());
+    capsule.discard().await.unwrap();
+    assert!(!kubeconfig.exists());
+}
+
+#[tokio::test]
+async fn omits_user_without_token() {
+    let kubectl = FakeKubectl::new("no-token");
+    let mut capsule = kubectl.capsule("");
+    capsule.encapsulate(String::from("no-token"), &kubectl.project(), PodOptions::default()).await.unwrap();
+    capsule.discard().await.unwrap();
+    assert!(kubectl.calls().iter().all(|c| !c.contains("--user")));
+    assert!(kubectl.read("kubeconfig").is_empty());
+}
+
+#[tokio::test]
+async fn creates_pod_from_manifest_and_waits_for_it() {
+    let kubectl = FakeKubectl::new("create");
+    let mut capsule = kubectl.capsule("");
+    capsule.encapsulate(String::from("create"), &kubectl.project(), PodOptions::default()).await.unwrap();
+    let calls = kubectl.calls();
+    assert!(calls[0].ends_with(" create --filename=-"), "{}", calls[0]);
+    assert!(calls[1].ends_with(" wait --for=condition=Ready --timeout=300s pod/rtk-capsule-create"), "{}", calls[1]);
+    assert!(calls[2].contains(" cp "), "{}", calls[2]);
+    let manifest: serde_json::Value = serde_json::from_str(&kubectl.read("manifest")).unwrap();
+    assert_eq!(manifest["metadata"]["name"], "rtk-capsule-create");
+    assert_eq!(manifest["spec"]["containers"][0]["image"], "alpine");
+    assert_eq!(kubectl.read("repo/data.txt"), "data");
+    capsule.discard().await.unwrap();
+    assert!(kubectl.calls().last().unwrap().ends_with(" delete pod rtk-capsule-create --ignore-not-found"));
+}
+
+#[tokio::test]
+async fn deletes_pod_if_create_fails() {
+    let kubectl = FakeKubectl::new("create-fails");
+    kubectl.fail("create");
+    let mut capsule = kubectl.capsule("secret-token");
+    let err = capsule.encapsulate(String::from("create-fails"), &kubectl.project(), PodOptions::default()).await.unwrap_err();
+    assert!(err.to_string().contains("create failed"), "{}", err);
+    assert!(kubectl.calls().last().unwrap().contains(" delete pod rtk-capsule-create-fails "));
+    let kubeconfig = PathBuf::from(kubectl.read("kubeconfig-path").trim());
+    assert!(!kubeconfig.exists());
+}
+
+#[tokio::test]
+async fn deletes_pod_if_it_does_not_become_ready() {
+    let kubectl = FakeKubectl::new("wait-fails");
+    kubectl.fail("wait");
+    let mut capsule = kubectl.capsule("");
+    let err = capsule.encapsulate(String::from("wait-fails"), &kubectl.project(), PodOptions::default()).await.unwrap_err();
+    assert!(err.to_string().contains("wait failed"), "{}", err);
+    let calls = kubectl.calls();
+    assert!(calls.iter().all(|c| !c.contains(" cp ")));
+    assert!(calls.last().unwrap().contains(" delete pod rtk-capsule-wait-fails "));
+}
+
+#[tokio::test]
+async fn runs_tests_in_pod() {
+    let kubectl = FakeKubectl::new("exec");
+    let mut capsule = kubectl.capsule("");
+    capsule.encapsulate(String::from("exec"), &kubectl.project(), PodOptions::default()).await.unwrap();
+    let mut env = TestEnv::default();
+    env.vars.insert(String::from("GREETING"), String::from("hello"));
+    let control = RunControl::default();
+    let output = capsule.run_test(0, &command("sh -c 'cat data.txt; echo \" $GREETING\"'"), &env, None, &control).await.unwrap();
+    assert_eq!(output.status, TestStatus::Passed);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "data hello\n");
+    let output = capsule.run_test(1, &command("sh -c 'echo failed >&2; exit 3'"), &env, None, &control).await.unwrap();
+    assert_eq!(output.status, TestStatus::Failed);
+    assert_eq!(output.exit_code, Some(3));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "failed\n");
+    // Values are passed on stdin, not as arguments
+    assert!(kubectl.calls().iter().all(|c| !c.contains("hello")));
+    capsule.discard().await.unwrap();
+}
+
+#[tokio::test]
+async fn names_pods_after_sanitized_ident() {
+    let kubectl = FakeKubectl::new("names");
+    let mut capsule = kubectl.capsule("");
+    capsule.encapsulate(String::from("My_Project.v2-1700000000000"), &kubectl.project(), PodOptions::default()).await.unwrap();
+    capsule.discard().await.unwrap();
+    let manifest: serde_json::Value = serde_json::from_str(&kubectl.read("manifest")).unwrap();
+    assert_eq!(manifest["metadata"]["name"], "rtk-capsule-my-project-v2-1700000000000");
+    // Long idents are shortened, keeping names of different runs apart
+    let mut names = Vec::new();
+    for run in ["1700000000000", "1700000000001"] {
+        let mut capsule = kubectl.capsule("");
+        capsule.encapsulate(format!("{}-{}", "a".repeat(80), run), &kubectl.project(), PodOptions::default()).await.unwrap();
+        capsule.discard().await.unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&kubectl.read("manifest")).unwrap();
+        let name = manifest["metadata"]["name"].as_str().unwrap().to_string();
+        assert!(name.len() <= 63, "{}", name);
+        assert!(name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'), "{}", name);
+        assert!(!name.ends_with('-'), "{}", name);
+        names.push(name);
+    }
+    assert_ne!(names[0], names[1]);
+}
+
+#[tokio::test]
+async fn rejects_empty_command() {
+    let kubectl = FakeKubectl::new("empty");
+    let mut capsule = kubectl.capsule("");
+    capsule.encapsulate(String::from("empty"), &kubectl.project(), PodOptions::default()).await.unwrap();
+    let err = capsule.run_test(0, &[], &TestEnv::default(), None, &RunControl::default()).await.unwrap_err();
+    assert_eq!(err.to_string(), "Test command is empty");
+    capsule.discard().await.unwrap();
+}
+
+#[tokio::test]
+async fn kills_timed_out_tests_in_pod() {
+    let kubectl = FakeKubectl::new("timeout");
+    let mut capsule = kubectl.capsule("");
+    capsule.encapsulate(String::from("timeout"), &kubectl.project(), PodOptions::default()).await.unwrap();
+    // Background processes started by the test are killed along with it
+    let output = capsule.run_test(0, &command("sh -c 'sleep 10 & echo $! > child.pid; wait'"), &TestEnv::default(), Some(Duration::from_millis(500)), &RunControl::default()).await.unwrap();
+    assert_eq!(output.status, TestStatus::TimedOut);
+    assert!(kubectl.calls().iter().any(|c| c.contains(" exec rtk-capsule-timeout -- sh -c exec kill -KILL -- -")));
+    let child: i32 = kubectl.read("repo/child.pid").trim().parse().unwrap();
+    assert!(exited(child), "process {} started by the test is still running", child);
+    capsule.discard().await.unwrap();
+}